@@ -1,5 +1,8 @@
 use mumble_protocol_rs::control::protobuf;
 
+/// Version the client announces, in the legacy `version` format of 2 bytes major, 1 byte minor and 1 byte patch.
+pub const CLIENT_VERSION: u32 = (1 << 16) | (5 << 8) | 18;
+
 pub struct MumbleClientInfo {
     os: String,
     os_version: String,
@@ -14,7 +17,7 @@ impl MumbleClientInfo {
             os: system_info.os_type().to_string(),
             os_version: system_info.version().to_string(),
             client_release_name: "mumble-client-rs:0.0.1".to_string(),
-            client_version: (1 << 16) | (5 << 8) | 18
+            client_version: CLIENT_VERSION
         }
     }
 }
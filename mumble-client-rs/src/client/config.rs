@@ -1,14 +1,69 @@
+use std::time::Duration;
+use rand::Rng;
+use crate::ClientCertificate;
+use crate::tls_configuration::ServerCertificateVerification;
+
+#[derive(Clone)]
 pub struct MumbleClientConfig {
     pub server_address: String,
     pub server_port: u16,
     pub override_tls_server_name: Option<String>,
     pub insecure_disable_certificate_verification: bool,
+    /// How the server certificate is checked, ignored if verification is disabled.
+    pub server_certificate_verification: ServerCertificateVerification,
     pub username: String,
-    pub password: Option<String>
+    pub password: Option<String>,
+    /// Access tokens granting entry to channels whose ACLs require a password.
+    pub access_tokens: Vec<String>,
+    /// Stays undeafened so that the server sends voice, see [StatefulMumbleClient::subscribe_to_voice_packets].
+    ///
+    /// [StatefulMumbleClient::subscribe_to_voice_packets]: crate::client::stateful_mumble_client::StatefulMumbleClient::subscribe_to_voice_packets
+    pub receive_audio: bool,
+    /// Listens to every channel so that the server sends voice from all of them and not just the
+    /// channel the client is in, requires [receive_audio](Self::receive_audio).
+    pub listen_to_all_channels: bool,
+    /// Certificate to authenticate with, required to connect as a registered user.
+    pub client_certificate: Option<ClientCertificate>,
+    /// Maximum time to establish the connection and wait for the server to sync its state.
+    pub connect_timeout: Duration,
+    pub reconnect_policy: ReconnectPolicy
 }
 
 impl MumbleClientConfig {
     pub fn connect_address(&self) -> String {
         format!("{}:{}", self.server_address, self.server_port)
     }
-}
\ No newline at end of file
+}
+
+/// Controls how a dropped connection to the server is re-established.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Stop reconnecting after this many failed attempts in a row, or retry forever if `None`.
+    pub max_attempts: Option<u32>
+}
+
+impl ReconnectPolicy {
+    /// Exponential backoff for the given (zero based) attempt, with up to half of the delay
+    /// replaced by random jitter so that multiple clients don't reconnect in lockstep.
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let backoff = self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter_range = backoff / 2;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=jitter_range);
+
+        backoff - jitter_range + jitter
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(300),
+            max_attempts: None
+        }
+    }
+}
@@ -1,8 +1,7 @@
-use std::error::Error;
 use std::io::ErrorKind;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use tokio::net::TcpStream;
+use tokio::net::{lookup_host, TcpStream};
 use rustls_pki_types::ServerName;
 use tokio_rustls::rustls::{ClientConfig};
 use tokio_rustls::TlsConnector;
@@ -13,42 +12,157 @@ use futures::SinkExt;
 use log::{debug, error, info, warn};
 use tokio::sync::{broadcast, mpsc};
 use tokio::task;
-use tokio::task::JoinHandle;
+use tokio::task::{AbortHandle, JoinHandle};
 use tokio::time;
 use tokio_rustls::client::TlsStream;
 use crate::client::client_info::MumbleClientInfo;
-use crate::MumbleClientConfig;
-use crate::tls_configuration::{create_root_certificate_store, NoCertificateVerification};
+use crate::{MumbleClientConfig, MumbleClientError};
+use crate::tls_configuration::{create_ca_file_certificate_store, create_root_certificate_store, CertificateFingerprint, FingerprintVerification, NoCertificateVerification, ServerCertificateVerification};
 
 pub struct RawMumbleClient {
     server_packet_broadcast_sender: broadcast::Sender<ControlPacket>,
-    client_packet_sender: mpsc::Sender<ControlPacket>
+    voice_packet_broadcast_sender: broadcast::Sender<protobuf::UdpTunnel>,
+    client_packet_sender: mpsc::Sender<ControlPacket>,
+    handshake_packets: Vec<ControlPacket>,
+    synced_receiver: Option<broadcast::Receiver<ControlPacket>>,
+    server_certificate_fingerprint: Option<CertificateFingerprint>
 }
 
 impl RawMumbleClient {
-    pub async fn connect(config: &MumbleClientConfig) -> Result<(RawMumbleClient, JoinHandle<()>), Box<dyn Error>> {
-        let (mut sink, stream) = establish_tls_connection(config).await.unwrap().split();
-        exchange_version_info(&mut sink).await;
-        authenticate_with_server(config, &mut sink).await;
-        mute_and_deafen(&mut sink).await;
+    /// Connects and authenticates with the server, only returning once the server has sent `ServerSync`.
+    /// Fails with [MumbleClientError::Rejected] if the server refuses the client, or with
+    /// [MumbleClientError::Timeout] if the handshake does not complete within the configured connect timeout.
+    pub async fn connect(config: &MumbleClientConfig) -> Result<(RawMumbleClient, JoinHandle<()>), MumbleClientError> {
+        let (sink, stream, handshake_packets, server_certificate_fingerprint) = time::timeout(config.connect_timeout, perform_handshake(config)).await
+            .map_err(|_| MumbleClientError::Timeout)??;
 
-        let (server_packet_broadcast_sender, _) = broadcast::channel(32);
+        let (server_packet_broadcast_sender, synced_receiver) = broadcast::channel(32);
+        // Every speaker sends up to 100 packets a second
+        let (voice_packet_broadcast_sender, _) = broadcast::channel(256);
         let (client_packet_sender, client_packet_receiver) = mpsc::channel(32);
 
-        let _client_packet_handler = task::spawn(process_client_packets(client_packet_receiver, sink));
-        let _server_packet_handler = task::spawn(broadcast_server_packets(server_packet_broadcast_sender.clone(), stream));
-        let _ping_server_on_interval = task::spawn(ping_server_on_interval(10, client_packet_sender.clone()));
+        let client_packet_handler = task::spawn(process_client_packets(client_packet_receiver, sink));
+        let server_packet_handler = task::spawn(broadcast_server_packets(
+            server_packet_broadcast_sender.clone(),
+            voice_packet_broadcast_sender.clone(),
+            stream));
+        let ping_server_on_interval = task::spawn(ping_server_on_interval(10, client_packet_sender.clone()));
+        let connection_handle = task::spawn(watch_connection(client_packet_handler, server_packet_handler, ping_server_on_interval));
 
-        Ok((Self { server_packet_broadcast_sender, client_packet_sender }, _client_packet_handler))
+        Ok((Self {
+            server_packet_broadcast_sender,
+            voice_packet_broadcast_sender,
+            client_packet_sender,
+            handshake_packets,
+            synced_receiver: Some(synced_receiver),
+            server_certificate_fingerprint
+        }, connection_handle))
     }
 
     pub fn get_sender(&self) -> mpsc::Sender<ControlPacket> {
         self.client_packet_sender.clone()
     }
 
+    pub async fn send(&self, packet: ControlPacket) -> Result<(), MumbleClientError> {
+        self.client_packet_sender.send(packet).await.map_err(|_| MumbleClientError::disconnected())
+    }
+
+    /// Every packet the server sends except tunnelled voice, which is received with
+    /// [subscribe_to_voice](Self::subscribe_to_voice).
     pub fn subscribe(&self) -> broadcast::Receiver<ControlPacket> {
         self.server_packet_broadcast_sender.subscribe()
     }
+
+    /// Voice the server tunnels through the control connection, kept apart from the other packets so a busy
+    /// channel doesn't crowd out state updates.
+    pub fn subscribe_to_voice(&self) -> broadcast::Receiver<protobuf::UdpTunnel> {
+        self.voice_packet_broadcast_sender.subscribe()
+    }
+
+    /// Takes the packets the server sent up to and including `ServerSync`, together with a receiver for every
+    /// packet after them. Once taken, later calls return no packets and a fresh subscription.
+    pub fn take_synced_subscription(&mut self) -> (Vec<ControlPacket>, broadcast::Receiver<ControlPacket>) {
+        let receiver = self.synced_receiver.take().unwrap_or_else(|| self.subscribe());
+        (std::mem::take(&mut self.handshake_packets), receiver)
+    }
+
+    /// Fingerprint of the certificate the server presented during the TLS handshake.
+    pub fn server_certificate_fingerprint(&self) -> Option<CertificateFingerprint> {
+        self.server_certificate_fingerprint
+    }
+}
+
+async fn perform_handshake(config: &MumbleClientConfig) -> Result<(
+    SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>,
+    SplitStream<Framed<TlsStream<TcpStream>, ControlCodec>>,
+    Vec<ControlPacket>,
+    Option<CertificateFingerprint>), MumbleClientError> {
+    let framed = establish_tls_connection(config).await?;
+    let server_certificate_fingerprint = framed.get_ref().get_ref().1.peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .map(CertificateFingerprint::of);
+    let (mut sink, mut stream) = framed.split();
+    exchange_version_info(&mut sink).await?;
+    authenticate_with_server(config, &mut sink).await?;
+    mute_and_deafen(&mut sink, !config.receive_audio).await?;
+    let handshake_packets = wait_for_server_sync(&mut stream).await?;
+
+    Ok((sink, stream, handshake_packets, server_certificate_fingerprint))
+}
+
+async fn wait_for_server_sync(stream: &mut SplitStream<Framed<TlsStream<TcpStream>, ControlCodec>>) -> Result<Vec<ControlPacket>, MumbleClientError> {
+    info!("Waiting for server to sync state");
+    let mut handshake_packets = vec![];
+    loop {
+        let packet = match stream.next().await {
+            Some(packet) => packet?,
+            None => return Err(MumbleClientError::Io(std::io::Error::new(ErrorKind::UnexpectedEof, "connection closed before server sync")))
+        };
+        debug!("Received Packet: {:?}", packet);
+
+        match packet {
+            ControlPacket::Reject(reject) => {
+                let reject_type = reject.r#type();
+                let reason = reject.reason.unwrap_or_else(|| reject_type.as_str_name().to_string());
+                return Err(MumbleClientError::Rejected { reject_type, reason });
+            },
+            // Without our own session the client can't tell which user it is
+            ControlPacket::ServerSync(sync) if sync.session.is_none() => {
+                return Err(MumbleClientError::ProtocolViolation("server sync without a session id".to_string()));
+            },
+            ControlPacket::ServerSync(_) => {
+                info!("Server sync complete");
+                handshake_packets.push(packet);
+                return Ok(handshake_packets);
+            },
+            packet => handshake_packets.push(packet)
+        }
+    }
+}
+
+/// Aborts the wrapped tasks when dropped, so that aborting the connection handle tears down the connection.
+struct AbortTasksOnDrop(Vec<AbortHandle>);
+
+impl Drop for AbortTasksOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Completes once either direction of the connection stops, taking the remaining connection tasks down with it.
+async fn watch_connection(client_packet_handler: JoinHandle<()>, server_packet_handler: JoinHandle<()>, ping_server_on_interval: JoinHandle<()>) {
+    let _connection_tasks = AbortTasksOnDrop(vec![
+        client_packet_handler.abort_handle(),
+        server_packet_handler.abort_handle(),
+        ping_server_on_interval.abort_handle()
+    ]);
+
+    tokio::select! {
+        _ = client_packet_handler => debug!("Client packet handler stopped"),
+        _ = server_packet_handler => debug!("Server packet handler stopped")
+    }
 }
 
 async fn ping_server_on_interval(interval: u64, packet_sender: mpsc::Sender<ControlPacket>) {
@@ -60,30 +174,58 @@ async fn ping_server_on_interval(interval: u64, packet_sender: mpsc::Sender<Cont
             ..Default::default()
         };
         debug!("Sending scheduled ping packet to server");
-        packet_sender.send(ping_packet.into()).await.unwrap();
+        if packet_sender.send(ping_packet.into()).await.is_err() {
+            debug!("Client packet channel closed, stopping scheduled pings");
+            return;
+        }
     }
 }
 
-async fn establish_tls_connection(config: &MumbleClientConfig) -> Result<Framed<TlsStream<TcpStream>, ControlCodec>, Box<dyn Error>> {
-    let mut tls_config = ClientConfig::builder()
-        .with_root_certificates(create_root_certificate_store().unwrap())
-        .with_no_client_auth();
+async fn establish_tls_connection(config: &MumbleClientConfig) -> Result<Framed<TlsStream<TcpStream>, ControlCodec>, MumbleClientError> {
+    let root_certificate_store = match &config.server_certificate_verification {
+        ServerCertificateVerification::CaFile(path) => create_ca_file_certificate_store(path)?,
+        _ => create_root_certificate_store()?
+    };
+    let tls_config_builder = ClientConfig::builder()
+        .with_root_certificates(root_certificate_store);
+    let mut tls_config = match &config.client_certificate {
+        Some(certificate) => tls_config_builder.with_client_auth_cert(
+            certificate.certificate_chain().to_vec(),
+            certificate.private_key().clone_key())?,
+        None => tls_config_builder.with_no_client_auth()
+    };
 
     if config.insecure_disable_certificate_verification {
         tls_config.dangerous().set_certificate_verifier(Arc::new(NoCertificateVerification {}));
     }
+    else {
+        let crypto_provider = tls_config.crypto_provider().clone();
+        let fingerprint_verification = match &config.server_certificate_verification {
+            ServerCertificateVerification::Fingerprint(fingerprint) => Some(FingerprintVerification::new(Some(*fingerprint), crypto_provider)),
+            ServerCertificateVerification::TrustOnFirstUse { known } => Some(FingerprintVerification::new(*known, crypto_provider)),
+            _ => None
+        };
+        if let Some(verifier) = fingerprint_verification {
+            tls_config.dangerous().set_certificate_verifier(Arc::new(verifier));
+        }
+    }
 
     let tls_server_name = match &config.override_tls_server_name {
         Some(server_name) => server_name,
         None => &config.server_address
     }.clone();
 
-    let dns_name = ServerName::try_from(tls_server_name).unwrap();
+    let dns_name = ServerName::try_from(tls_server_name.clone())
+        .map_err(|err| MumbleClientError::Dns { host: tls_server_name, reason: err.to_string() })?;
 
     info!("Connecting to mumble server: {}", config.connect_address());
 
+    let server_addresses = lookup_host(config.connect_address()).await
+        .map_err(|err| MumbleClientError::Dns { host: config.server_address.clone(), reason: err.to_string() })?
+        .collect::<Vec<_>>();
+
     let connector = TlsConnector::from(Arc::new(tls_config));
-    let tcp_stream = TcpStream::connect(config.connect_address().as_str()).await?;
+    let tcp_stream = TcpStream::connect(server_addresses.as_slice()).await?;
     let tls_stream = connector.connect(dns_name, tcp_stream).await?;
 
     info!("TLS connection established to mumble server");
@@ -91,22 +233,24 @@ async fn establish_tls_connection(config: &MumbleClientConfig) -> Result<Framed<
     Ok(ControlCodec::new().framed(tls_stream))
 }
 
-async fn exchange_version_info(sink: &mut SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>) {
+async fn exchange_version_info(sink: &mut SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>) -> Result<(), MumbleClientError> {
     info!("Exchanging version information");
     let client_info: protobuf::Version = MumbleClientInfo::from_system().into();
-    sink.send(client_info.into()).await.unwrap();
+    sink.send(client_info.into()).await?;
+    Ok(())
 }
 
-async fn authenticate_with_server(config: &MumbleClientConfig, sink: &mut SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>) {
+async fn authenticate_with_server(config: &MumbleClientConfig, sink: &mut SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>) -> Result<(), MumbleClientError> {
     info!("Authenticating with server");
     let client_authentication_message = protobuf::Authenticate {
         opus: Some(true),
         username: config.username.clone().into(),
         celt_versions: Vec::new(),
         password: config.password.clone(),
-        tokens: Vec::new()
+        tokens: config.access_tokens.clone()
     };
-    sink.send(client_authentication_message.into()).await.unwrap();
+    sink.send(client_authentication_message.into()).await?;
+    Ok(())
 }
 
 async fn process_client_packets(mut packet_receiver: mpsc::Receiver<ControlPacket>, mut sink: SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>) {
@@ -125,7 +269,10 @@ async fn process_client_packets(mut packet_receiver: mpsc::Receiver<ControlPacke
     }
 }
 
-async fn broadcast_server_packets(packet_broadcaster: broadcast::Sender<ControlPacket>, mut stream: SplitStream<Framed<TlsStream<TcpStream>, ControlCodec>>) {
+async fn broadcast_server_packets(
+    packet_broadcaster: broadcast::Sender<ControlPacket>,
+    voice_packet_broadcaster: broadcast::Sender<protobuf::UdpTunnel>,
+    mut stream: SplitStream<Framed<TlsStream<TcpStream>, ControlCodec>>) {
     loop {
         let stream_item = stream.next().await;
         match stream_item {
@@ -133,6 +280,10 @@ async fn broadcast_server_packets(packet_broadcaster: broadcast::Sender<ControlP
                 warn!("Server connection closed, no more packets will be received");
                 return;
             }
+            Some(Ok(ControlPacket::UdpTunnel(tunnel))) => {
+                // Nobody listening is not an error, voice is only handled on demand
+                let _ = voice_packet_broadcaster.send(*tunnel);
+            },
             Some(Ok(packet)) => {
                 debug!("Received Packet: {:?}", packet);
                 if let Err(send_error) = packet_broadcaster.send(packet) {
@@ -146,14 +297,15 @@ async fn broadcast_server_packets(packet_broadcaster: broadcast::Sender<ControlP
     }
 }
 
-async fn mute_and_deafen(sink: &mut SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>) {
-    info!("Muting and deafening bot user");
+async fn mute_and_deafen(sink: &mut SplitSink<Framed<TlsStream<TcpStream>, ControlCodec>, ControlPacket>, deafen: bool) -> Result<(), MumbleClientError> {
+    info!("Muting {}bot user", if deafen { "and deafening " } else { "" });
     let bot_user_state_packet = protobuf::UserState {
         self_mute: true.into(),
-        self_deaf: true.into(),
+        self_deaf: deafen.into(),
         ..Default::default()
     };
-    sink.send(bot_user_state_packet.into()).await.unwrap();
+    sink.send(bot_user_state_packet.into()).await?;
+    Ok(())
 }
 
 fn get_unix_timestamp() -> u64 {
@@ -2,22 +2,38 @@ pub mod server;
 pub mod channel;
 pub mod user;
 pub mod event;
+pub mod text_message;
+#[cfg(feature = "audio")]
+pub mod audio;
+#[cfg(feature = "audio")]
+pub mod playback;
 
 use std::collections::HashMap;
-use std::error::Error;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::error::RecvError;
 use std::sync::Mutex;
-use log::error;
-use tokio::sync::broadcast;
+use log::{error, info, warn};
+use tokio::sync::{broadcast, mpsc};
 use tokio::task::JoinHandle;
-use mumble_protocol_rs::control::{ControlPacket};
+use tokio::time;
+use mumble_protocol_rs::control::{ControlPacket, protobuf};
+use mumble_protocol_rs::voice::{PacketOrigin, UdpPacket, VoicePacket};
 use crate::client::stateful_mumble_client::channel::ChannelState;
 use crate::client::stateful_mumble_client::server::ServerState;
+use crate::client::stateful_mumble_client::text_message::TextMessage;
 use crate::client::stateful_mumble_client::user::UserState;
-use crate::{MumbleClientConfig, RawMumbleClient};
+use crate::{MumbleClientConfig, MumbleClientError, RawMumbleClient};
+use crate::tls_configuration::ServerCertificateVerification;
 pub use crate::client::stateful_mumble_client::event::MumbleEvent;
 
+/// Time without voice after which a user counts as no longer talking, bridging short pauses between words.
+const TALKING_HANG_TIME: Duration = Duration::from_millis(500);
+/// How often users are checked for having stopped talking.
+const TALKING_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
 struct State {
     server: ServerState,
     channels: HashMap<u32, ChannelState>,
@@ -25,58 +41,432 @@ struct State {
 }
 
 pub struct StatefulMumbleClient {
-    pub raw_client: RawMumbleClient,
+    client_packet_sender: mpsc::Sender<ControlPacket>,
     event_sender: broadcast::Sender<MumbleEvent>,
-    state: Arc<Mutex<State>>
+    voice_packet_sender: broadcast::Sender<VoicePacket>,
+    state: Arc<Mutex<State>>,
+    access_tokens: Arc<Mutex<Vec<String>>>,
+    /// Number of the next frame sent, held while playing so that playbacks don't overlap.
+    #[cfg(feature = "audio")]
+    next_voice_frame_number: tokio::sync::Mutex<u64>
 }
 
 impl StatefulMumbleClient {
-    pub async fn connect(config: &MumbleClientConfig) -> Result<(StatefulMumbleClient, JoinHandle<()>), Box<dyn Error>> {
-        let (raw_client, server_connected_handle) = RawMumbleClient::connect(config).await?;
+    /// Connects to the server and keeps the connection alive, reconnecting according to the configured
+    /// [ReconnectPolicy](crate::client::config::ReconnectPolicy). The first connect is retried the same way,
+    /// errors that won't go away by retrying are returned right away. The returned handle completes once the
+    /// client gives up reconnecting.
+    pub async fn connect(config: &MumbleClientConfig) -> Result<(StatefulMumbleClient, JoinHandle<()>), MumbleClientError> {
+        let connection = connect_with_backoff(config).await?;
+
+        let mut state = State::default();
+        state.server.certificate_fingerprint = connection.0.server_certificate_fingerprint();
+        let state = Arc::new(Mutex::new(state));
 
-        let state = Arc::new(Mutex::new(State {
-            server: ServerState::default(),
-            channels: HashMap::new(),
-            users: HashMap::new()
-        }));
+        let access_tokens = Arc::new(Mutex::new(config.access_tokens.clone()));
 
         let (mumble_event_broadcast_sender, _) = broadcast::channel(32);
+        // Every speaker sends up to 100 packets a second
+        let (voice_packet_sender, _) = broadcast::channel(256);
+        let (client_packet_sender, client_packet_receiver) = mpsc::channel(32);
 
-        tokio::spawn(raw_client_event_handler(raw_client.subscribe(), state.clone(), mumble_event_broadcast_sender.clone()));
+        let supervisor_handle = tokio::spawn(supervise_connection(
+            config.clone(),
+            connection,
+            client_packet_receiver,
+            state.clone(),
+            access_tokens.clone(),
+            mumble_event_broadcast_sender.clone(),
+            voice_packet_sender.clone()));
 
         Ok((StatefulMumbleClient {
-            raw_client,
+            client_packet_sender,
             event_sender: mumble_event_broadcast_sender,
-            state
-        }, server_connected_handle))
+            voice_packet_sender,
+            state,
+            access_tokens,
+            #[cfg(feature = "audio")]
+            next_voice_frame_number: tokio::sync::Mutex::new(0)
+        }, supervisor_handle))
+    }
+
+    /// Sender for packets to the server which stays valid across reconnects.
+    pub fn get_sender(&self) -> mpsc::Sender<ControlPacket> {
+        self.client_packet_sender.clone()
+    }
+
+    pub async fn send(&self, packet: ControlPacket) -> Result<(), MumbleClientError> {
+        self.client_packet_sender.send(packet).await.map_err(|_| MumbleClientError::disconnected())
     }
 
     pub fn subscribe_to_mumble_events(&self) -> Receiver<MumbleEvent> {
         self.event_sender.subscribe()
     }
 
+    /// Voice packets the server tunnels through the control connection. The server only sends voice if
+    /// [receive_audio](MumbleClientConfig::receive_audio) is set.
+    pub fn subscribe_to_voice_packets(&self) -> Receiver<VoicePacket> {
+        self.voice_packet_sender.subscribe()
+    }
+
+    /// Decodes the voice of every user who talks into a separate stream. Requires
+    /// [receive_audio](MumbleClientConfig::receive_audio) to be set.
+    #[cfg(feature = "audio")]
+    pub fn receive_audio(&self) -> audio::AudioReceiver {
+        audio::AudioReceiver::new(self.subscribe_to_voice_packets(), self.subscribe_to_mumble_events())
+    }
+
+    /// Plays audio to [TARGET_NORMAL](mumble_protocol_rs::voice::packet::TARGET_NORMAL), the client's channel, or
+    /// a target registered with [set_voice_target](Self::set_voice_target), returning once it has been played.
+    /// Playbacks wait for the previous one to finish. The client is unmuted while playing.
+    #[cfg(feature = "audio")]
+    pub async fn play_audio(&self, source: playback::AudioSource, target: u32) -> Result<(), MumbleClientError> {
+        let packets = tokio::task::spawn_blocking(move || source.into_opus_packets()).await
+            .map_err(|err| MumbleClientError::Audio(format!("audio encoder failed: {}", err)))??;
+
+        let mut frame_number = self.next_voice_frame_number.lock().await;
+        let (own_user, format) = {
+            let state = self.state.lock().unwrap();
+            let own_user = state.server.user_session_id.and_then(|session_id| state.users.get(&session_id)).cloned();
+            (own_user, state.server.voice_packet_format())
+        };
+        let own_user = own_user.ok_or_else(MumbleClientError::disconnected)?;
+
+        // The server drops voice of muted users, unmuting also undeafens
+        if own_user.muted || own_user.deafened {
+            self.send(protobuf::UserState {
+                session: Some(own_user.session_id),
+                self_mute: Some(false),
+                ..Default::default()
+            }.into()).await?;
+        }
+        let result = playback::send_opus_packets(&self.client_packet_sender, packets, target, format, &mut frame_number).await;
+        if own_user.muted || own_user.deafened {
+            let restore_result = self.send(protobuf::UserState {
+                session: Some(own_user.session_id),
+                self_mute: Some(own_user.muted),
+                self_deaf: Some(own_user.deafened),
+                ..Default::default()
+            }.into()).await;
+            // Whether the audio was played matters more to the caller
+            if let Err(err) = restore_result {
+                warn!("Unable to mute the client again after playing audio: {}", err);
+            }
+        }
+        result
+    }
+
+    /// Registers voice target `id`, between 1 and 30, to talk to the given users and channels.
+    pub async fn set_voice_target(&self, id: u32, targets: Vec<protobuf::voice_target::Target>) -> Result<(), MumbleClientError> {
+        let voice_target_packet = protobuf::VoiceTarget {
+            id: Some(id),
+            targets
+        };
+        self.send(voice_target_packet.into()).await
+    }
+
     pub fn get_current_online_users(&self) -> Vec<UserState> {
         let state = self.state.lock().unwrap();
         state.users.values().cloned().collect()
     }
+
+    pub fn get_channels(&self) -> Vec<ChannelState> {
+        let state = self.state.lock().unwrap();
+        state.channels.values().cloned().collect()
+    }
+
+    pub fn get_server_state(&self) -> ServerState {
+        let state = self.state.lock().unwrap();
+        state.server.clone()
+    }
+
+    /// Posts a text message to the given channel.
+    pub async fn send_text_message(&self, channel_id: u32, message: String) -> Result<(), MumbleClientError> {
+        let text_message_packet = protobuf::TextMessage {
+            channel_id: vec![channel_id],
+            message,
+            ..Default::default()
+        };
+        self.send(text_message_packet.into()).await
+    }
+
+    /// Replaces the access tokens of the current session, which are also sent when reconnecting.
+    pub async fn set_access_tokens(&self, tokens: Vec<String>) -> Result<(), MumbleClientError> {
+        *self.access_tokens.lock().unwrap() = tokens.clone();
+        let authenticate_packet = protobuf::Authenticate {
+            tokens,
+            ..Default::default()
+        };
+        self.send(authenticate_packet.into()).await
+    }
 }
 
-async fn raw_client_event_handler(mut receiver: Receiver<ControlPacket>, mut state: Arc<Mutex<State>>, event_sender: broadcast::Sender<MumbleEvent>) {
-    let mut startup_finished = false;
-    while let Ok(packet) = receiver.recv().await {
-        if matches!(&packet, ControlPacket::ServerSync(_)) {
-            startup_finished = true;
+enum ConnectionOutcome {
+    Disconnected,
+    /// Packets were skipped, so the state no longer matches the server's.
+    OutOfSync,
+    ClientDropped
+}
+
+async fn supervise_connection(
+    mut config: MumbleClientConfig,
+    mut connection: (RawMumbleClient, JoinHandle<()>),
+    mut client_packet_receiver: mpsc::Receiver<ControlPacket>,
+    mut state: Arc<Mutex<State>>,
+    access_tokens: Arc<Mutex<Vec<String>>>,
+    event_sender: broadcast::Sender<MumbleEvent>,
+    voice_packet_sender: broadcast::Sender<VoicePacket>) {
+    let mut is_reconnect = false;
+    loop {
+        let (mut raw_client, mut connection_handle) = connection;
+        let server_certificate_fingerprint = raw_client.server_certificate_fingerprint();
+        if let ServerCertificateVerification::TrustOnFirstUse { known: known @ None } = &mut config.server_certificate_verification {
+            // Pin the certificate seen first so a reconnect can't be intercepted
+            *known = server_certificate_fingerprint;
         }
-        for event in handle_control_packet(packet, &mut state).await {
-            if startup_finished {
-                if let Err(err) = event_sender.send(event) {
-                    error!("Error sending mumble event: {}", err);
+        state.lock().unwrap().server.certificate_fingerprint = server_certificate_fingerprint;
+
+        let (handshake_packets, receiver) = raw_client.take_synced_subscription();
+        let voice_receiver = raw_client.subscribe_to_voice();
+        for packet in handshake_packets {
+            handle_control_packet(packet, &mut state).await;
+        }
+        request_online_times(&raw_client, &state).await;
+        if is_reconnect {
+            info!("Reconnected to mumble server");
+            send_event(&event_sender, MumbleEvent::Reconnected);
+        }
+
+        let listener = config.listen_to_all_channels.then(|| raw_client.get_sender());
+        if let Some(listener) = &listener {
+            let channel_ids = state.lock().unwrap().channels.keys().copied().collect();
+            listen_to_channels(listener, &state, channel_ids).await;
+        }
+
+        let mut event_handler = tokio::spawn(raw_client_event_handler(
+            receiver,
+            voice_receiver,
+            state.clone(),
+            event_sender.clone(),
+            voice_packet_sender.clone(),
+            listener));
+        let outcome = forward_client_packets(&raw_client, &mut connection_handle, &mut event_handler, &mut client_packet_receiver).await;
+        event_handler.abort();
+        // Nobody is heard anymore, so transmissions end with the connection
+        let stopped_talking: Vec<MumbleEvent> = state.lock().unwrap().users.values_mut()
+            .filter_map(|user| user.stop_talking())
+            .collect();
+        for event in stopped_talking {
+            send_event(&event_sender, event);
+        }
+        *state.lock().unwrap() = State::default();
+
+        if matches!(outcome, ConnectionOutcome::ClientDropped) {
+            info!("Mumble client dropped, closing connection");
+            connection_handle.abort();
+            return;
+        }
+
+        match outcome {
+            ConnectionOutcome::OutOfSync => {
+                // The server can't be asked for its whole state again, reconnecting gets it afresh
+                warn!("Lost track of the mumble server's state, reconnecting to resync");
+                connection_handle.abort();
+            },
+            _ => warn!("Lost connection to mumble server")
+        }
+        send_event(&event_sender, MumbleEvent::Disconnected);
+
+        config.access_tokens = access_tokens.lock().unwrap().clone();
+        connection = match reconnect_with_backoff(&config).await {
+            Some(connection) => connection,
+            None => return
+        };
+        is_reconnect = true;
+    }
+}
+
+async fn forward_client_packets(
+    raw_client: &RawMumbleClient,
+    connection_handle: &mut JoinHandle<()>,
+    event_handler: &mut JoinHandle<()>,
+    client_packet_receiver: &mut mpsc::Receiver<ControlPacket>) -> ConnectionOutcome {
+    loop {
+        tokio::select! {
+            _ = &mut *connection_handle => return ConnectionOutcome::Disconnected,
+            _ = &mut *event_handler => return ConnectionOutcome::OutOfSync,
+            packet = client_packet_receiver.recv() => match packet {
+                Some(packet) => {
+                    if raw_client.send(packet).await.is_err() {
+                        return ConnectionOutcome::Disconnected;
+                    }
+                },
+                None => return ConnectionOutcome::ClientDropped
+            }
+        }
+    }
+}
+
+/// Connects for the first time, retrying the failures [reconnect_with_backoff] would retry.
+async fn connect_with_backoff(config: &MumbleClientConfig) -> Result<(RawMumbleClient, JoinHandle<()>), MumbleClientError> {
+    let policy = &config.reconnect_policy;
+    let mut attempt = 0;
+    loop {
+        match RawMumbleClient::connect(config).await {
+            Ok(connection) => return Ok(connection),
+            Err(err) if err.is_retryable() && !policy.max_attempts.is_some_and(|max_attempts| attempt >= max_attempts) => {
+                let delay = policy.delay_for_attempt(attempt);
+                warn!("Failed to connect to mumble server, retrying in {:?}: {}", delay, err);
+                time::sleep(delay).await;
+            },
+            Err(err) => return Err(err)
+        }
+        attempt += 1;
+    }
+}
+
+async fn reconnect_with_backoff(config: &MumbleClientConfig) -> Option<(RawMumbleClient, JoinHandle<()>)> {
+    let policy = &config.reconnect_policy;
+    let mut attempt = 0;
+    loop {
+        if policy.max_attempts.is_some_and(|max_attempts| attempt >= max_attempts) {
+            error!("Giving up reconnecting to mumble server after {} attempts", attempt);
+            return None;
+        }
+
+        let delay = policy.delay_for_attempt(attempt);
+        info!("Reconnecting to mumble server in {:?}", delay);
+        time::sleep(delay).await;
+
+        match RawMumbleClient::connect(config).await {
+            Ok(connection) => return Some(connection),
+            Err(err) if err.is_retryable() => warn!("Failed to reconnect to mumble server: {}", err),
+            Err(err) => {
+                error!("Giving up reconnecting to mumble server: {}", err);
+                return None;
+            }
+        }
+        attempt += 1;
+    }
+}
+
+fn send_event(event_sender: &broadcast::Sender<MumbleEvent>, event: MumbleEvent) {
+    if let Err(err) = event_sender.send(event) {
+        error!("Error sending mumble event: {}", err);
+    }
+}
+
+/// Asks the server how long the users already online have been connected, which is answered with `UserStats`.
+async fn request_online_times(raw_client: &RawMumbleClient, state: &Arc<Mutex<State>>) {
+    let session_ids: Vec<u32> = state.lock().unwrap().users.keys().copied().collect();
+    for session_id in session_ids {
+        let user_stats_packet = protobuf::UserStats {
+            session: Some(session_id),
+            stats_only: Some(true),
+            ..Default::default()
+        };
+        if raw_client.send(user_stats_packet.into()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Asks the server to send voice from the given channels in addition to the client's own channel.
+async fn listen_to_channels(sender: &mpsc::Sender<ControlPacket>, state: &Arc<Mutex<State>>, channel_ids: Vec<u32>) {
+    let session = state.lock().unwrap().server.user_session_id;
+    if session.is_none() || channel_ids.is_empty() {
+        return;
+    }
+    let listen_packet = protobuf::UserState {
+        session,
+        listening_channel_add: channel_ids,
+        ..Default::default()
+    };
+    if sender.send(listen_packet.into()).await.is_err() {
+        warn!("Unable to listen to channels, connection closed");
+    }
+}
+
+/// Applies the server's packets to the state until the connection closes, or until packets had to be skipped.
+async fn raw_client_event_handler(
+    mut receiver: Receiver<ControlPacket>,
+    mut voice_receiver: Receiver<protobuf::UdpTunnel>,
+    mut state: Arc<Mutex<State>>,
+    event_sender: broadcast::Sender<MumbleEvent>,
+    voice_packet_sender: broadcast::Sender<VoicePacket>,
+    listener: Option<mpsc::Sender<ControlPacket>>) {
+    let mut talking_check = time::interval(TALKING_CHECK_INTERVAL);
+    loop {
+        let packet = tokio::select! {
+            packet = receiver.recv() => match packet {
+                Ok(packet) => packet,
+                Err(RecvError::Lagged(count)) => {
+                    warn!("Mumble event handler lagging behind, skipped {} packets", count);
+                    return;
+                },
+                Err(RecvError::Closed) => return
+            },
+            tunnel = voice_receiver.recv() => {
+                match tunnel {
+                    Ok(tunnel) => {
+                        if let Some(event) = handle_tunnelled_voice_packet(&tunnel.packet, &state, &voice_packet_sender) {
+                            send_event(&event_sender, event);
+                        }
+                    },
+                    // Lost voice is only a gap in the audio
+                    Err(RecvError::Lagged(count)) => warn!("Voice handler lagging behind, skipped {} voice packets", count),
+                    Err(RecvError::Closed) => return
+                }
+                continue;
+            },
+            _ = talking_check.tick() => {
+                for event in stop_silent_users(&state) {
+                    send_event(&event_sender, event);
                 }
+                continue;
+            }
+        };
+
+        for event in handle_control_packet(packet, &mut state).await {
+            if let (MumbleEvent::ChannelCreated(channel), Some(listener)) = (&event, &listener) {
+                listen_to_channels(listener, &state, vec![channel.id]).await;
             }
+            send_event(&event_sender, event);
+        }
+    }
+}
+
+fn handle_tunnelled_voice_packet(
+    packet: &[u8],
+    state: &Arc<Mutex<State>>,
+    voice_packet_sender: &broadcast::Sender<VoicePacket>) -> Option<MumbleEvent> {
+    let mut state = state.lock().unwrap();
+    let format = state.server.voice_packet_format();
+    match UdpPacket::decode(packet, format, PacketOrigin::Server) {
+        Ok(UdpPacket::Voice(voice_packet)) => {
+            let event = voice_packet.session_id
+                .and_then(|session_id| state.users.get_mut(&session_id))
+                .and_then(|user| user.update_from_voice_packet(Instant::now()));
+            // Nobody listening is not an error, voice is only decoded on demand
+            let _ = voice_packet_sender.send(voice_packet);
+            event
+        },
+        Ok(UdpPacket::Ping(_)) => None,
+        Err(err) => {
+            warn!("Unable to decode voice packet: {}", err);
+            None
         }
     }
 }
 
+fn stop_silent_users(state: &Arc<Mutex<State>>) -> Vec<MumbleEvent> {
+    let now = Instant::now();
+    let mut state = state.lock().unwrap();
+    state.users.values_mut()
+        .filter_map(|user| user.stop_talking_after(TALKING_HANG_TIME, now))
+        .collect()
+}
+
 async fn handle_control_packet(packet: ControlPacket, state: &mut Arc<Mutex<State>>) -> Vec<MumbleEvent> {
     match packet {
         ControlPacket::ServerSync(s) => {
@@ -134,6 +524,11 @@ async fn handle_control_packet(packet: ControlPacket, state: &mut Arc<Mutex<Stat
                         current_channel_id: u.channel_id,
                         deafened: u.self_deaf.unwrap_or(false),
                         muted: u.self_mute.unwrap_or(false),
+                        recording: u.recording.unwrap_or(false),
+                        connected_at: Instant::now(),
+                        talking_since: None,
+                        talk_time: Duration::ZERO,
+                        last_voice_packet_at: None
                     };
 
                     state.users.insert(u.session.unwrap(), new_user.clone());
@@ -144,13 +539,35 @@ async fn handle_control_packet(packet: ControlPacket, state: &mut Arc<Mutex<Stat
         ControlPacket::UserRemove(u) => {
             let mut state = state.lock().unwrap();
             match state.users.remove(&u.session) {
-                Some(user) => vec![MumbleEvent::UserLeftServer(user)],
+                Some(mut user) => {
+                    let mut events: Vec<MumbleEvent> = user.stop_talking().into_iter().collect();
+                    events.push(MumbleEvent::UserLeftServer(user));
+                    events
+                },
                 None => vec![]
             }
         }
+        ControlPacket::UserStats(s) => {
+            let mut state = state.lock().unwrap();
+            let user = s.session.and_then(|session_id| state.users.get_mut(&session_id));
+            let connected_at = s.onlinesecs.and_then(|online_seconds| Instant::now().checked_sub(Duration::from_secs(online_seconds.into())));
+            match (user, connected_at) {
+                (Some(user), Some(connected_at)) => {
+                    user.connected_at = connected_at;
+                    vec![MumbleEvent::UserUpdated(user.clone())]
+                },
+                _ => vec![]
+            }
+        },
         ControlPacket::TextMessage(t) => {
             let state = state.lock().unwrap();
-            vec![MumbleEvent::TextMessagePosted(t.message, t.actor.and_then(|actor_id| state.users.get(&actor_id)).cloned())]
+            vec![MumbleEvent::TextMessagePosted(TextMessage {
+                sender: t.actor.and_then(|actor_id| state.users.get(&actor_id)).cloned(),
+                message: t.message,
+                channel_ids: t.channel_id,
+                tree_ids: t.tree_id,
+                session_ids: t.session
+            })]
         }
         _ => vec![]
     }
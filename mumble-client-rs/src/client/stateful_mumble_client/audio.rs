@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use audiopus::coder::Decoder;
+use audiopus::packet::Packet;
+use audiopus::{Channels, MutSignals, SampleRate};
+use bytes::Bytes;
+use futures::Stream;
+use log::{debug, error, warn};
+use mumble_protocol_rs::voice::{VoicePacket, VoicePayload};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+use crate::client::stateful_mumble_client::MumbleEvent;
+
+/// Sample rate of decoded audio, which is always mono.
+pub const SAMPLE_RATE: u32 = 48_000;
+/// Samples in the 10 ms frames voice packets are numbered in.
+pub const SAMPLES_PER_FRAME: usize = SAMPLE_RATE as usize / 100;
+
+/// Packets held back to reorder late ones before playing the first of them.
+const JITTER_BUFFER_PACKETS: usize = 3;
+/// Time without packets after which the jitter buffer is played out, as the speaker stopped without a terminator.
+const JITTER_BUFFER_TIMEOUT: Duration = Duration::from_millis(200);
+/// Gaps up to this many frames are filled in by the decoder, longer ones are skipped.
+const MAX_CONCEALED_FRAMES: u64 = 12;
+/// A packet this far behind the expected one starts a new transmission instead of being dropped as late.
+const SEQUENCE_RESET_FRAMES: u64 = 1000;
+/// Opus packets hold at most 120 ms of audio.
+const MAX_PACKET_SAMPLES: usize = SAMPLES_PER_FRAME * 12;
+
+/// Decoded audio of a single voice packet, or audio interpolated for lost packets.
+#[derive(Clone, Debug)]
+pub struct AudioFrame {
+    /// Number of the first 10 ms frame, as counted by the speaker's client.
+    pub frame_number: u64,
+    /// Mono samples at [SAMPLE_RATE].
+    pub samples: Vec<i16>,
+    /// The samples were interpolated because packets were lost.
+    pub concealed: bool,
+    /// The speaker stopped talking after this frame.
+    pub end_of_transmission: bool
+}
+
+/// Audio of one user, ending when the user leaves the server or the client disconnects.
+pub struct UserAudioStream {
+    session_id: u32,
+    receiver: mpsc::Receiver<AudioFrame>
+}
+
+impl UserAudioStream {
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    pub async fn next_frame(&mut self) -> Option<AudioFrame> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for UserAudioStream {
+    type Item = AudioFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Hands out a [UserAudioStream] for every user who starts talking. Decoding stops once this is dropped.
+pub struct AudioReceiver {
+    streams: mpsc::Receiver<UserAudioStream>
+}
+
+impl AudioReceiver {
+    pub(crate) fn new(voice_packets: broadcast::Receiver<VoicePacket>, events: broadcast::Receiver<MumbleEvent>) -> Self {
+        let (sender, streams) = mpsc::channel(16);
+        tokio::spawn(route_voice_packets(voice_packets, events, sender));
+        Self { streams }
+    }
+
+    /// Waits for a user who talks for the first time.
+    pub async fn next_stream(&mut self) -> Option<UserAudioStream> {
+        self.streams.recv().await
+    }
+}
+
+impl Stream for AudioReceiver {
+    type Item = UserAudioStream;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.streams.poll_recv(cx)
+    }
+}
+
+async fn route_voice_packets(
+    mut voice_packets: broadcast::Receiver<VoicePacket>,
+    mut events: broadcast::Receiver<MumbleEvent>,
+    streams: mpsc::Sender<UserAudioStream>) {
+    // Users whose audio can't be decoded are kept as `None` until they leave, so they are only logged once
+    let mut users: HashMap<u32, Option<mpsc::Sender<VoicePacket>>> = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = streams.closed() => return,
+            packet = voice_packets.recv() => match packet {
+                Ok(packet) => {
+                    let Some(session_id) = packet.session_id else { continue };
+                    if !matches!(packet.payload, VoicePayload::Opus { .. }) {
+                        debug!("Ignoring voice packet of user {} in a codec other than opus", session_id);
+                        continue;
+                    }
+                    let user = match users.entry(session_id) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => entry.insert(start_user_decoder(session_id, &streams))
+                    };
+                    let Some(user) = user else { continue };
+                    match user.try_send(packet) {
+                        Ok(()) => {},
+                        Err(TrySendError::Full(_)) => warn!("Audio decoder of user {} can't keep up, dropping voice packet", session_id),
+                        // The stream was dropped, the user's next packet offers a new one
+                        Err(TrySendError::Closed(_)) => { users.remove(&session_id); }
+                    }
+                },
+                Err(RecvError::Lagged(count)) => warn!("Audio receiver lagging behind, skipped {} voice packets", count),
+                Err(RecvError::Closed) => return
+            },
+            event = events.recv() => match event {
+                Ok(MumbleEvent::UserLeftServer(user)) => { users.remove(&user.session_id); },
+                Ok(MumbleEvent::Disconnected) => users.clear(),
+                Ok(_) | Err(RecvError::Lagged(_)) => {},
+                Err(RecvError::Closed) => return
+            }
+        }
+    }
+}
+
+/// Hands out a stream for the user and starts decoding into it, `None` if the user's audio can't be decoded or
+/// isn't taken.
+fn start_user_decoder(session_id: u32, streams: &mpsc::Sender<UserAudioStream>) -> Option<mpsc::Sender<VoicePacket>> {
+    let decoder = match Decoder::new(SampleRate::Hz48000, Channels::Mono) {
+        Ok(decoder) => decoder,
+        Err(err) => {
+            error!("Unable to create opus decoder for user {}: {}", session_id, err);
+            return None;
+        }
+    };
+    let (packet_sender, packet_receiver) = mpsc::channel(64);
+    let (frame_sender, frame_receiver) = mpsc::channel(64);
+    // Waiting for the consumer to take the stream would hold up the audio of everyone else
+    match streams.try_send(UserAudioStream { session_id, receiver: frame_receiver }) {
+        Ok(()) => {},
+        Err(TrySendError::Full(_)) => {
+            warn!("Audio receiver isn't taking new streams, skipping the audio of user {}", session_id);
+            return None;
+        },
+        // The router stops once it notices
+        Err(TrySendError::Closed(_)) => return None
+    }
+    tokio::spawn(decode_user_audio(session_id, decoder, packet_receiver, frame_sender));
+    Some(packet_sender)
+}
+
+async fn decode_user_audio(
+    session_id: u32,
+    mut decoder: Decoder,
+    mut packets: mpsc::Receiver<VoicePacket>,
+    frames: mpsc::Sender<AudioFrame>) {
+    let mut jitter_buffer = JitterBuffer::default();
+    loop {
+        tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(packet) => jitter_buffer.push(packet),
+                None => return
+            },
+            _ = time::sleep(JITTER_BUFFER_TIMEOUT), if !jitter_buffer.is_empty() => jitter_buffer.drain()
+        }
+
+        while let Some(output) = jitter_buffer.pop() {
+            let frame = match output {
+                JitterBufferOutput::Packet { frame_number, data, terminator } => {
+                    let mut samples = vec![0; MAX_PACKET_SAMPLES];
+                    let decoded = Packet::try_from(data.as_ref())
+                        .and_then(|packet| decoder.decode(Some(packet), MutSignals::try_from(&mut samples)?, false));
+                    let decoded = match decoded {
+                        Ok(decoded) => decoded,
+                        Err(err) => {
+                            warn!("Unable to decode voice packet of user {}: {}", session_id, err);
+                            0
+                        }
+                    };
+                    samples.truncate(decoded);
+                    jitter_buffer.played(frame_number, (decoded / SAMPLES_PER_FRAME).max(1) as u64, terminator);
+                    AudioFrame { frame_number, samples, concealed: false, end_of_transmission: terminator }
+                },
+                JitterBufferOutput::Lost { frame_number, frames } => {
+                    let mut samples = vec![0; frames as usize * SAMPLES_PER_FRAME];
+                    let decoded = MutSignals::try_from(&mut samples)
+                        .and_then(|signals| decoder.decode(None, signals, false))
+                        .unwrap_or(0);
+                    samples.truncate(decoded);
+                    AudioFrame { frame_number, samples, concealed: true, end_of_transmission: false }
+                }
+            };
+            if frames.send(frame).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum JitterBufferOutput {
+    Packet { frame_number: u64, data: Bytes, terminator: bool },
+    Lost { frame_number: u64, frames: u64 }
+}
+
+/// Orders the packets of one user by frame number, waiting for a few packets before giving up on a missing one.
+#[derive(Default)]
+struct JitterBuffer {
+    packets: BTreeMap<u64, (Bytes, bool)>,
+    /// Frame expected next, `None` at the start of a transmission.
+    next_frame: Option<u64>,
+    draining: bool
+}
+
+impl JitterBuffer {
+    fn push(&mut self, packet: VoicePacket) {
+        let VoicePayload::Opus { data, terminator } = packet.payload else { return };
+        if let Some(next_frame) = self.next_frame {
+            if packet.frame_number < next_frame {
+                if next_frame - packet.frame_number < SEQUENCE_RESET_FRAMES {
+                    debug!("Dropping late voice packet {}", packet.frame_number);
+                    return;
+                }
+                // The speaker's client restarted counting
+                self.packets.clear();
+                self.next_frame = None;
+            }
+        }
+        self.packets.insert(packet.frame_number, (data, terminator));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Plays out all buffered packets without waiting for missing ones.
+    fn drain(&mut self) {
+        self.draining = true;
+    }
+
+    fn pop(&mut self) -> Option<JitterBufferOutput> {
+        let Some(&first_frame) = self.packets.keys().next() else {
+            self.draining = false;
+            return None;
+        };
+        let ready = self.draining
+            || self.packets.len() >= JITTER_BUFFER_PACKETS
+            || self.packets.values().any(|(_, terminator)| *terminator);
+
+        match self.next_frame {
+            Some(next_frame) if next_frame == first_frame => {},
+            Some(next_frame) if next_frame > first_frame => {
+                debug!("Dropping voice packet {} overlapping the previous one", first_frame);
+                self.packets.remove(&first_frame);
+                return self.pop();
+            },
+            Some(next_frame) if ready => {
+                self.next_frame = Some(first_frame);
+                let frames = first_frame - next_frame;
+                if frames <= MAX_CONCEALED_FRAMES {
+                    return Some(JitterBufferOutput::Lost { frame_number: next_frame, frames });
+                }
+            },
+            None if ready => {},
+            _ => return None
+        }
+
+        let (data, terminator) = self.packets.remove(&first_frame)?;
+        Some(JitterBufferOutput::Packet { frame_number: first_frame, data, terminator })
+    }
+
+    /// Advances past a played packet spanning `frames` frames.
+    fn played(&mut self, frame_number: u64, frames: u64, terminator: bool) {
+        self.next_frame = if terminator { None } else { Some(frame_number + frames) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mumble_protocol_rs::voice::AudioHeader;
+    use super::*;
+
+    /// Frames in each test packet, 20 ms like the Mumble client sends.
+    const FRAMES: u64 = 2;
+
+    fn packet(frame_number: u64, terminator: bool) -> VoicePacket {
+        VoicePacket {
+            header: AudioHeader::Target(0),
+            session_id: Some(1),
+            frame_number,
+            payload: VoicePayload::Opus { data: Bytes::from(frame_number.to_be_bytes().to_vec()), terminator },
+            position: None,
+            volume_adjustment: None
+        }
+    }
+
+    fn played(frame_number: u64, terminator: bool) -> JitterBufferOutput {
+        JitterBufferOutput::Packet { frame_number, data: Bytes::from(frame_number.to_be_bytes().to_vec()), terminator }
+    }
+
+    fn push_all(jitter_buffer: &mut JitterBuffer, frame_numbers: &[u64]) {
+        for &frame_number in frame_numbers {
+            jitter_buffer.push(packet(frame_number, false));
+        }
+    }
+
+    /// Pops everything that is ready, playing it like the decoder does.
+    fn play(jitter_buffer: &mut JitterBuffer) -> Vec<JitterBufferOutput> {
+        let mut outputs = vec![];
+        while let Some(output) = jitter_buffer.pop() {
+            if let JitterBufferOutput::Packet { frame_number, terminator, .. } = output {
+                jitter_buffer.played(frame_number, FRAMES, terminator);
+            }
+            outputs.push(output);
+        }
+        outputs
+    }
+
+    #[test]
+    fn waits_for_enough_packets_before_playing() {
+        let mut jitter_buffer = JitterBuffer::default();
+        push_all(&mut jitter_buffer, &[0, 2]);
+        assert!(play(&mut jitter_buffer).is_empty());
+
+        push_all(&mut jitter_buffer, &[4]);
+        assert_eq!(play(&mut jitter_buffer), [played(0, false), played(2, false), played(4, false)]);
+        assert!(jitter_buffer.is_empty());
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let mut jitter_buffer = JitterBuffer::default();
+        push_all(&mut jitter_buffer, &[4, 0, 2]);
+        assert_eq!(play(&mut jitter_buffer), [played(0, false), played(2, false), played(4, false)]);
+    }
+
+    #[test]
+    fn plays_duplicates_once() {
+        let mut jitter_buffer = JitterBuffer::default();
+        push_all(&mut jitter_buffer, &[0, 2, 2, 4]);
+        assert_eq!(play(&mut jitter_buffer), [played(0, false), played(2, false), played(4, false)]);
+
+        push_all(&mut jitter_buffer, &[4]);
+        assert!(jitter_buffer.is_empty());
+    }
+
+    #[test]
+    fn drops_packets_arriving_after_their_turn() {
+        let mut jitter_buffer = JitterBuffer::default();
+        push_all(&mut jitter_buffer, &[0, 4, 6, 8]);
+        assert_eq!(play(&mut jitter_buffer), [
+            played(0, false),
+            JitterBufferOutput::Lost { frame_number: 2, frames: 2 },
+            played(4, false),
+            played(6, false),
+            played(8, false)
+        ]);
+
+        push_all(&mut jitter_buffer, &[2]);
+        assert!(jitter_buffer.is_empty());
+    }
+
+    #[test]
+    fn conceals_short_gaps_and_skips_long_ones() {
+        let mut jitter_buffer = JitterBuffer::default();
+        push_all(&mut jitter_buffer, &[0, 6, 8, 10]);
+        assert_eq!(play(&mut jitter_buffer), [
+            played(0, false),
+            JitterBufferOutput::Lost { frame_number: 2, frames: 4 },
+            played(6, false),
+            played(8, false),
+            played(10, false)
+        ]);
+
+        push_all(&mut jitter_buffer, &[100, 102, 104]);
+        assert_eq!(play(&mut jitter_buffer), [played(100, false), played(102, false), played(104, false)]);
+    }
+
+    #[test]
+    fn flushes_transmission_on_terminator() {
+        let mut jitter_buffer = JitterBuffer::default();
+        jitter_buffer.push(packet(0, false));
+        jitter_buffer.push(packet(2, true));
+        assert_eq!(play(&mut jitter_buffer), [played(0, false), played(2, true)]);
+
+        // The next transmission starts without a gap to conceal
+        push_all(&mut jitter_buffer, &[50, 52, 54]);
+        assert_eq!(play(&mut jitter_buffer), [played(50, false), played(52, false), played(54, false)]);
+    }
+
+    #[test]
+    fn drains_transmission_without_terminator() {
+        let mut jitter_buffer = JitterBuffer::default();
+        push_all(&mut jitter_buffer, &[0]);
+        assert!(play(&mut jitter_buffer).is_empty());
+
+        jitter_buffer.drain();
+        assert_eq!(play(&mut jitter_buffer), [played(0, false)]);
+        // Once drained the buffer waits for missing packets again
+        push_all(&mut jitter_buffer, &[6]);
+        assert!(play(&mut jitter_buffer).is_empty());
+    }
+}
@@ -1,5 +1,7 @@
+use std::time::Duration;
 use crate::client::stateful_mumble_client::channel::ChannelState;
 use crate::client::stateful_mumble_client::server::ServerState;
+use crate::client::stateful_mumble_client::text_message::TextMessage;
 use crate::client::stateful_mumble_client::user::UserState;
 
 #[derive(Clone)]
@@ -8,9 +10,20 @@ pub enum MumbleEvent {
     UserJoinedServer(UserState),
     UserLeftServer(UserState),
     UserSwitchedChannel(UserState),
+    /// The user muted, unmuted, deafened or undeafened themselves.
+    UserMuteChanged(UserState),
     UserUpdated(UserState),
+    /// Voice was received from a user who was silent, requires
+    /// [receive_audio](crate::MumbleClientConfig::receive_audio).
+    UserStartedTalking(UserState),
+    /// The user stopped talking after talking for the given duration.
+    UserStoppedTalking(UserState, Duration),
     ChannelCreated(ChannelState),
     ChannelUpdated(ChannelState),
     ChannelDeleted(ChannelState),
-    TextMessagePosted(String, Option<UserState>),
+    TextMessagePosted(TextMessage),
+    /// The connection to the server was lost, all channel and user state has been cleared.
+    Disconnected,
+    /// The connection to the server was re-established and the state has been resynced.
+    Reconnected,
 }
@@ -0,0 +1,195 @@
+use std::io::Cursor;
+use std::path::Path;
+use std::time::Duration;
+use audiopus::coder::Encoder;
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use bytes::{Bytes, BytesMut};
+use hound::{SampleFormat, WavReader};
+use ogg::reading::PacketReader;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use mumble_protocol_rs::control::{protobuf, ControlPacket};
+use mumble_protocol_rs::voice::{AudioHeader, UdpPacket, VoicePacket, VoicePacketFormat, VoicePayload};
+use crate::client::stateful_mumble_client::audio::{SAMPLES_PER_FRAME, SAMPLE_RATE};
+use crate::MumbleClientError;
+
+/// Encoded packets hold 20 ms of audio, like those of the Mumble client.
+const SAMPLES_PER_PACKET: usize = SAMPLES_PER_FRAME * 2;
+/// The Mumble client's default quality.
+const BITRATE: i32 = 40_000;
+/// Largest packet the encoder may produce, as recommended by libopus.
+const MAX_PACKET_SIZE: usize = 4000;
+const FRAME_DURATION: Duration = Duration::from_millis(10);
+
+/// Audio to play with [StatefulMumbleClient::play_audio](super::StatefulMumbleClient::play_audio).
+pub enum AudioSource {
+    /// An Ogg/Opus stream like a Telegram voice note, whose packets are sent without re-encoding them. The
+    /// stream's pre-skip can therefore only be honoured for whole packets.
+    OggOpus(Vec<u8>),
+    /// A WAV file, mixed down to mono, resampled to [SAMPLE_RATE] and encoded.
+    Wav(Vec<u8>),
+    /// Mono samples at [SAMPLE_RATE], encoded.
+    Pcm(Vec<i16>)
+}
+
+impl AudioSource {
+    /// Reads an Ogg/Opus or WAV file, telling them apart by their content.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, MumbleClientError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .map_err(|err| MumbleClientError::Audio(format!("unable to read {}: {}", path.display(), err)))?;
+        match data.get(..4) {
+            Some(b"OggS") => Ok(AudioSource::OggOpus(data)),
+            Some(b"RIFF") => Ok(AudioSource::Wav(data)),
+            _ => Err(MumbleClientError::Audio(format!("{} is neither an Ogg nor a WAV file", path.display())))
+        }
+    }
+
+    /// Encodes the audio if needed. This is CPU bound and should not run on the async runtime.
+    pub(crate) fn into_opus_packets(self) -> Result<Vec<OpusPacket>, MumbleClientError> {
+        match self {
+            AudioSource::OggOpus(data) => read_ogg_opus(data),
+            AudioSource::Wav(data) => encode(&read_wav(data)?),
+            AudioSource::Pcm(samples) => encode(&samples)
+        }
+    }
+}
+
+pub(crate) struct OpusPacket {
+    data: Bytes,
+    /// Number of 10 ms frames in the packet.
+    frames: u64
+}
+
+fn read_ogg_opus(data: Vec<u8>) -> Result<Vec<OpusPacket>, MumbleClientError> {
+    let ogg_error = |err: ogg::OggReadError| MumbleClientError::Audio(format!("invalid Ogg stream: {}", err));
+    let mut reader = PacketReader::new(Cursor::new(data));
+
+    let header = reader.read_packet().map_err(ogg_error)?
+        .filter(|header| header.data.starts_with(b"OpusHead"))
+        .ok_or_else(|| MumbleClientError::Audio("the Ogg stream doesn't contain Opus audio".to_string()))?;
+    // Samples of encoder delay at the start of the stream, which players are meant to discard
+    let mut pre_skip = header.data.get(10..12).map_or(0, |pre_skip| u16::from_le_bytes([pre_skip[0], pre_skip[1]]) as usize);
+    // The comment header follows the identification header
+    reader.read_packet().map_err(ogg_error)?;
+
+    let mut packets = vec![];
+    while let Some(packet) = reader.read_packet().map_err(ogg_error)? {
+        let samples = Packet::try_from(packet.data.as_slice())
+            .and_then(|opus_packet| audiopus::packet::nb_samples(opus_packet, SampleRate::Hz48000))
+            .map_err(|err| MumbleClientError::Audio(format!("invalid Opus packet: {}", err)))?;
+        if samples <= pre_skip {
+            pre_skip -= samples;
+            continue;
+        }
+        // A packet can't be cut without re-encoding it, so the rest of the delay is played
+        pre_skip = 0;
+        packets.push(OpusPacket {
+            data: Bytes::from(packet.data),
+            frames: (samples / SAMPLES_PER_FRAME).max(1) as u64
+        });
+    }
+    Ok(packets)
+}
+
+fn read_wav(data: Vec<u8>) -> Result<Vec<i16>, MumbleClientError> {
+    let wav_error = |err: hound::Error| MumbleClientError::Audio(format!("invalid WAV file: {}", err));
+    let mut reader = WavReader::new(Cursor::new(data)).map_err(wav_error)?;
+    let spec = reader.spec();
+    if spec.sample_rate == 0 || spec.channels == 0 {
+        return Err(MumbleClientError::Audio("invalid WAV file: no sample rate or channels".to_string()));
+    }
+
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 * scale)).collect()
+        }
+    }.map_err(wav_error)?;
+
+    let channels = spec.channels as usize;
+    let mono: Vec<f32> = samples.chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok(resample(&mono, spec.sample_rate).into_iter()
+        .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+        .collect())
+}
+
+/// Linear interpolation, which is good enough for notification sounds and speech.
+fn resample(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    if sample_rate == SAMPLE_RATE || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let step = sample_rate as f64 / SAMPLE_RATE as f64;
+    let length = (samples.len() as f64 / step) as usize;
+    (0..length)
+        .map(|index| {
+            let position = index as f64 * step;
+            let sample_index = position as usize;
+            let fraction = (position - sample_index as f64) as f32;
+            let sample = samples[sample_index];
+            let next_sample = samples.get(sample_index + 1).copied().unwrap_or(sample);
+            sample + (next_sample - sample) * fraction
+        })
+        .collect()
+}
+
+fn encode(samples: &[i16]) -> Result<Vec<OpusPacket>, MumbleClientError> {
+    let encoder_error = |err: audiopus::Error| MumbleClientError::Audio(format!("unable to encode audio: {}", err));
+    let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Audio).map_err(encoder_error)?;
+    encoder.set_bitrate(Bitrate::BitsPerSecond(BITRATE)).map_err(encoder_error)?;
+
+    let mut output = vec![0; MAX_PACKET_SIZE];
+    samples.chunks(SAMPLES_PER_PACKET)
+        .map(|chunk| {
+            // The last packet is padded with silence
+            let mut input = chunk.to_vec();
+            input.resize(SAMPLES_PER_PACKET, 0);
+            let length = encoder.encode(&input, &mut output).map_err(encoder_error)?;
+            Ok(OpusPacket {
+                data: Bytes::copy_from_slice(&output[..length]),
+                frames: (SAMPLES_PER_PACKET / SAMPLES_PER_FRAME) as u64
+            })
+        })
+        .collect()
+}
+
+/// Tunnels the packets through the control connection in real time, marking the last one as the end of the
+/// transmission. `frame_number` continues the count of previous transmissions.
+pub(crate) async fn send_opus_packets(
+    sender: &mpsc::Sender<ControlPacket>,
+    mut packets: Vec<OpusPacket>,
+    target: u32,
+    format: VoicePacketFormat,
+    frame_number: &mut u64) -> Result<(), MumbleClientError> {
+    if packets.is_empty() {
+        // Even without audio the transmission is ended, which takes a terminator without any data
+        packets.push(OpusPacket { data: Bytes::new(), frames: 0 });
+    }
+    let started_at = Instant::now();
+    let mut played = Duration::ZERO;
+    let packet_count = packets.len();
+    for (index, packet) in packets.into_iter().enumerate() {
+        let voice_packet = VoicePacket {
+            header: AudioHeader::Target(target),
+            session_id: None,
+            frame_number: *frame_number,
+            payload: VoicePayload::Opus { data: packet.data, terminator: index + 1 == packet_count },
+            position: None,
+            volume_adjustment: None
+        };
+        let mut encoded = BytesMut::new();
+        UdpPacket::Voice(voice_packet).encode(format, &mut encoded)?;
+        let tunnel_packet = protobuf::UdpTunnel { packet: encoded.to_vec() };
+        sender.send(tunnel_packet.into()).await.map_err(|_| MumbleClientError::disconnected())?;
+
+        *frame_number += packet.frames;
+        played += FRAME_DURATION * packet.frames as u32;
+        time::sleep_until(started_at + played).await;
+    }
+    Ok(())
+}
@@ -1,6 +1,10 @@
+use std::time::Instant;
 use mumble_protocol_rs::control::protobuf;
 use mumble_protocol_rs::control::protobuf::Version;
+use mumble_protocol_rs::voice::VoicePacketFormat;
+use crate::client::client_info::CLIENT_VERSION;
 use crate::client::stateful_mumble_client::MumbleEvent;
+use crate::tls_configuration::CertificateFingerprint;
 
 pub type ServerInfo = Version;
 
@@ -14,15 +18,26 @@ pub struct ServerState {
     pub max_message_length: Option<u32>,
     pub max_image_message_length: Option<u32>,
     pub max_users: Option<u32>,
-    pub startup_finished: bool
+    pub startup_finished: bool,
+    /// When the client finished connecting, `None` while disconnected.
+    pub synced_at: Option<Instant>,
+    /// Fingerprint of the certificate the server presented for the current connection.
+    pub certificate_fingerprint: Option<CertificateFingerprint>
 }
 
 impl ServerState {
+    /// Format of voice packets exchanged with the server, known once the server sent its version.
+    pub fn voice_packet_format(&self) -> VoicePacketFormat {
+        let server_version = self.server_info.as_ref().and_then(|info| info.version).unwrap_or_default();
+        VoicePacketFormat::negotiate(CLIENT_VERSION, server_version)
+    }
+
     pub fn update_from_server_sync(&mut self, packet: protobuf::ServerSync) -> Vec<MumbleEvent> {
         self.welcome_text = packet.welcome_text;
         self.max_bandwidth = packet.max_bandwidth;
         self.user_session_id = packet.session;
         self.startup_finished = true;
+        self.synced_at = Some(Instant::now());
         
         vec![]
     }
@@ -0,0 +1,17 @@
+use crate::client::stateful_mumble_client::user::UserState;
+
+#[derive(Clone)]
+pub struct TextMessage {
+    pub sender: Option<UserState>,
+    pub message: String,
+    pub channel_ids: Vec<u32>,
+    pub tree_ids: Vec<u32>,
+    pub session_ids: Vec<u32>
+}
+
+impl TextMessage {
+    /// Whether the message was sent directly to users rather than posted to a channel or channel tree.
+    pub fn is_private(&self) -> bool {
+        self.channel_ids.is_empty() && self.tree_ids.is_empty()
+    }
+}
@@ -1,3 +1,4 @@
+use std::time::{Duration, Instant};
 use mumble_protocol_rs::control::protobuf;
 use crate::client::stateful_mumble_client::MumbleEvent;
 
@@ -8,7 +9,16 @@ pub struct UserState {
     pub current_channel_id: Option<u32>,
     pub name: String,
     pub muted: bool,
-    pub deafened: bool
+    pub deafened: bool,
+    pub recording: bool,
+    /// When the user connected to the server. For users already online when the client connects this is taken
+    /// from the online time the server reports, until then it's when the client first saw them.
+    pub connected_at: Instant,
+    /// When the current transmission started, `None` while the user is silent.
+    pub talking_since: Option<Instant>,
+    /// Time spent talking in finished transmissions since the client first saw this user.
+    pub talk_time: Duration,
+    pub(crate) last_voice_packet_at: Option<Instant>
 }
 
 impl UserState {
@@ -16,6 +26,50 @@ impl UserState {
         self.name.ends_with("Bot")
     }
 
+    pub fn online_duration(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    pub fn is_talking(&self) -> bool {
+        self.talking_since.is_some()
+    }
+
+    /// Talk time including the current transmission.
+    pub fn total_talk_time(&self) -> Duration {
+        self.talk_time + self.talking_since.map(|since| since.elapsed()).unwrap_or_default()
+    }
+
+    /// Records a voice packet, returning an event if the user was silent before.
+    pub(crate) fn update_from_voice_packet(&mut self, received_at: Instant) -> Option<MumbleEvent> {
+        self.last_voice_packet_at = Some(received_at);
+        if self.talking_since.is_some() {
+            return None;
+        }
+        self.talking_since = Some(received_at);
+        Some(MumbleEvent::UserStartedTalking(self.clone()))
+    }
+
+    /// Ends the current transmission once no voice was received for `hang_time`. The transmission is
+    /// counted until the last voice packet, the hang time itself isn't talk time.
+    pub(crate) fn stop_talking_after(&mut self, hang_time: Duration, now: Instant) -> Option<MumbleEvent> {
+        let talking_since = self.talking_since?;
+        let last_voice_packet_at = self.last_voice_packet_at.unwrap_or(talking_since);
+        if now.duration_since(last_voice_packet_at) < hang_time {
+            return None;
+        }
+        self.stop_talking()
+    }
+
+    /// Ends the current transmission at the last voice packet, returning an event if the user was talking.
+    pub(crate) fn stop_talking(&mut self) -> Option<MumbleEvent> {
+        let talking_since = self.talking_since?;
+        let last_voice_packet_at = self.last_voice_packet_at.unwrap_or(talking_since);
+        let duration = last_voice_packet_at.duration_since(talking_since);
+        self.talking_since = None;
+        self.talk_time += duration;
+        Some(MumbleEvent::UserStoppedTalking(self.clone(), duration))
+    }
+
     pub fn update_from_user_state_packet(&mut self, packet: protobuf::UserState) -> Vec<MumbleEvent> {
         let mut entity_events = vec![];
         let mut state_changed = false;
@@ -32,14 +86,23 @@ impl UserState {
             state_changed = true;
             self.name = packet.name.unwrap();
         }
+        let mut mute_changed = false;
         if packet.self_mute.is_some() && packet.self_mute.as_ref() != Some(&self.muted) {
-            state_changed = true;
+            mute_changed = true;
             self.muted = packet.self_mute.unwrap();
         }
         if packet.self_deaf.is_some() && packet.self_deaf != Some(self.deafened) {
-            state_changed = true;
+            mute_changed = true;
             self.deafened = packet.self_deaf.unwrap();
         }
+        if let Some(recording) = packet.recording.filter(|recording| *recording != self.recording) {
+            state_changed = true;
+            self.recording = recording;
+        }
+        if mute_changed {
+            state_changed = true;
+            entity_events.push(MumbleEvent::UserMuteChanged(self.clone()))
+        }
 
         if state_changed {
             entity_events.push(MumbleEvent::UserUpdated(self.clone()))
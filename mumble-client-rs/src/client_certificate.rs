@@ -0,0 +1,189 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::{BufReader, Write};
+use std::path::Path;
+use log::info;
+use p12_keystore::KeyStore;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use crate::MumbleClientError;
+
+/// Certificate the client authenticates itself with. Mumble identifies registered users by their certificate,
+/// so a client needs one to register and be granted permissions through ACLs.
+pub struct ClientCertificate {
+    certificate_chain: Vec<CertificateDer<'static>>,
+    private_key: PrivateKeyDer<'static>
+}
+
+impl ClientCertificate {
+    pub fn new(certificate_chain: Vec<CertificateDer<'static>>, private_key: PrivateKeyDer<'static>) -> Self {
+        Self { certificate_chain, private_key }
+    }
+
+    /// Loads a PEM encoded certificate chain and private key, which may both be stored in the same file.
+    pub fn from_pem_files(certificate_path: impl AsRef<Path>, private_key_path: impl AsRef<Path>) -> Result<Self, MumbleClientError> {
+        let certificate_path = certificate_path.as_ref();
+        let private_key_path = private_key_path.as_ref();
+
+        let mut certificate_reader = BufReader::new(fs::File::open(certificate_path)?);
+        let certificate_chain = rustls_pemfile::certs(&mut certificate_reader).collect::<Result<Vec<_>, _>>()?;
+        if certificate_chain.is_empty() {
+            return Err(MumbleClientError::Certificate(format!("no certificate found in {}", certificate_path.display())));
+        }
+
+        let mut private_key_reader = BufReader::new(fs::File::open(private_key_path)?);
+        let private_key = rustls_pemfile::private_key(&mut private_key_reader)?
+            .ok_or_else(|| MumbleClientError::Certificate(format!("no private key found in {}", private_key_path.display())))?;
+
+        Ok(Self::new(certificate_chain, private_key))
+    }
+
+    /// Loads the first private key and its certificate chain from a PKCS#12 (`.p12` / `.pfx`) file.
+    pub fn from_pkcs12_file(path: impl AsRef<Path>, password: &str) -> Result<Self, MumbleClientError> {
+        let path = path.as_ref();
+        let key_store = KeyStore::from_pkcs12(&fs::read(path)?, password)
+            .map_err(|err| MumbleClientError::Certificate(format!("unable to read {}: {}", path.display(), err)))?;
+        let (_, key_chain) = key_store.private_key_chain()
+            .ok_or_else(|| MumbleClientError::Certificate(format!("no private key found in {}", path.display())))?;
+
+        let certificate_chain = key_chain.chain().iter()
+            .map(|certificate| CertificateDer::from(certificate.as_der().to_vec()))
+            .collect();
+        let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_chain.key().to_vec()));
+
+        Ok(Self::new(certificate_chain, private_key))
+    }
+
+    /// Generates a self-signed certificate, the way the Mumble client does for new users.
+    pub fn generate_self_signed(common_name: &str) -> Result<(Self, GeneratedPem), MumbleClientError> {
+        let certificate_error = |err: rcgen::Error| MumbleClientError::Certificate(format!("unable to generate certificate: {}", err));
+
+        let key_pair = KeyPair::generate().map_err(certificate_error)?;
+        let mut params = CertificateParams::new(Vec::new()).map_err(certificate_error)?;
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        let certificate = params.self_signed(&key_pair).map_err(certificate_error)?;
+
+        let pem = GeneratedPem { certificate: certificate.pem(), private_key: key_pair.serialize_pem() };
+        let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+        Ok((Self::new(vec![certificate.der().clone()], private_key), pem))
+    }
+
+    /// Loads the PEM files, or generates a self-signed certificate and saves it to them if the certificate
+    /// file doesn't exist yet, so the client keeps its identity across restarts. A private key without its
+    /// certificate is never overwritten, since the identity it belongs to would be lost.
+    pub fn load_or_generate(
+        certificate_path: impl AsRef<Path>,
+        private_key_path: impl AsRef<Path>,
+        common_name: &str) -> Result<Self, MumbleClientError> {
+        let certificate_path = certificate_path.as_ref();
+        let private_key_path = private_key_path.as_ref();
+        if certificate_path.exists() {
+            return Self::from_pem_files(certificate_path, private_key_path);
+        }
+        if certificate_path != private_key_path && private_key_path.exists() {
+            return Err(MumbleClientError::Certificate(format!(
+                "{} is missing but the private key {} exists, restore the certificate or remove the key",
+                certificate_path.display(),
+                private_key_path.display())));
+        }
+
+        info!("Generating client certificate {}", certificate_path.display());
+        let (certificate, pem) = Self::generate_self_signed(common_name)?;
+        if certificate_path == private_key_path {
+            write_private_key(certificate_path, &format!("{}{}", pem.certificate, pem.private_key))?;
+        }
+        else {
+            write_private_key(private_key_path, &pem.private_key)?;
+            fs::write(certificate_path, pem.certificate)?;
+        }
+
+        Ok(certificate)
+    }
+
+    pub fn certificate_chain(&self) -> &[CertificateDer<'static>] {
+        &self.certificate_chain
+    }
+
+    pub fn private_key(&self) -> &PrivateKeyDer<'static> {
+        &self.private_key
+    }
+}
+
+impl Clone for ClientCertificate {
+    fn clone(&self) -> Self {
+        Self::new(self.certificate_chain.clone(), self.private_key.clone_key())
+    }
+}
+
+impl Debug for ClientCertificate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ClientCertificate({} certificates)", self.certificate_chain.len())
+    }
+}
+
+/// Creates a file only the owner can read, failing if it already exists.
+fn write_private_key(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// PEM encoding of a generated certificate and its private key, for storing them.
+pub struct GeneratedPem {
+    pub certificate: String,
+    pub private_key: String
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("mumble-client-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reloads_generated_certificate() {
+        let dir = temp_dir("reload");
+        let (certificate_path, private_key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+
+        let generated = ClientCertificate::load_or_generate(&certificate_path, &private_key_path, "bot").unwrap();
+        let loaded = ClientCertificate::load_or_generate(&certificate_path, &private_key_path, "bot").unwrap();
+        assert_eq!(generated.certificate_chain(), loaded.certificate_chain());
+        assert_eq!(generated.private_key().secret_der(), loaded.private_key().secret_der());
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&private_key_path).unwrap().permissions()) & 0o777, 0o600);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_generated_combined_file() {
+        let dir = temp_dir("combined");
+        let path = dir.join("identity.pem");
+
+        let generated = ClientCertificate::load_or_generate(&path, &path, "bot").unwrap();
+        let loaded = ClientCertificate::load_or_generate(&path, &path, "bot").unwrap();
+        assert_eq!(generated.certificate_chain(), loaded.certificate_chain());
+        assert_eq!(generated.private_key().secret_der(), loaded.private_key().secret_der());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_overwrite_key_without_certificate() {
+        let dir = temp_dir("orphaned-key");
+        let (certificate_path, private_key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&private_key_path, "existing key").unwrap();
+
+        assert!(ClientCertificate::load_or_generate(&certificate_path, &private_key_path, "bot").is_err());
+        assert_eq!(fs::read_to_string(&private_key_path).unwrap(), "existing key");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
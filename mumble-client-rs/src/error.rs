@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::{CertificateError, OtherError};
+use mumble_protocol_rs::control::protobuf::reject::RejectType;
+use crate::tls_configuration::FingerprintMismatch;
+
+#[derive(Debug)]
+pub enum MumbleClientError {
+    /// The TLS handshake failed, e.g. because the server certificate is not trusted.
+    Tls(rustls::Error),
+    /// The server address could not be resolved or is not a valid TLS server name.
+    Dns { host: String, reason: String },
+    Io(io::Error),
+    /// The server refused to let the client in.
+    Rejected { reject_type: RejectType, reason: String },
+    /// The server did not respond in time.
+    Timeout,
+    /// The server sent something the client did not expect at this point of the protocol.
+    ProtocolViolation(String),
+    /// A certificate or fingerprint could not be loaded, generated or parsed.
+    Certificate(String),
+    /// Audio could not be read, decoded or encoded.
+    Audio(String)
+}
+
+impl MumbleClientError {
+    /// Whether trying again later could succeed without changing the client configuration.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            MumbleClientError::Dns { .. } | MumbleClientError::Io(_) | MumbleClientError::Timeout => true,
+            // A previous session of ours may still be held by the server, or someone may leave
+            MumbleClientError::Rejected { reject_type, .. } => matches!(reject_type, RejectType::UsernameInUse | RejectType::ServerFull),
+            _ => false
+        }
+    }
+
+    /// Details if the server presented a different certificate than the one whose fingerprint was trusted.
+    pub fn fingerprint_mismatch(&self) -> Option<&FingerprintMismatch> {
+        match self {
+            MumbleClientError::Tls(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(err)))) => err.downcast_ref(),
+            _ => None
+        }
+    }
+
+    pub(crate) fn disconnected() -> Self {
+        MumbleClientError::Io(io::Error::new(io::ErrorKind::NotConnected, "not connected to mumble server"))
+    }
+}
+
+impl Display for MumbleClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MumbleClientError::Tls(err) => write!(f, "TLS error: {}", err),
+            MumbleClientError::Dns { host, reason } => write!(f, "Unable to resolve {}: {}", host, reason),
+            MumbleClientError::Io(err) => write!(f, "IO error: {}", err),
+            MumbleClientError::Rejected { reject_type, reason } => write!(f, "Rejected by server ({}): {}", reject_type.as_str_name(), reason),
+            MumbleClientError::Timeout => write!(f, "Timed out waiting for server"),
+            MumbleClientError::ProtocolViolation(message) => write!(f, "Protocol violation: {}", message),
+            MumbleClientError::Certificate(message) => write!(f, "Certificate error: {}", message),
+            MumbleClientError::Audio(message) => write!(f, "Audio error: {}", message)
+        }
+    }
+}
+
+impl Error for MumbleClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MumbleClientError::Tls(err) => Some(err),
+            MumbleClientError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for MumbleClientError {
+    fn from(err: io::Error) -> Self {
+        // tokio-rustls reports handshake failures as IO errors wrapping the rustls error
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+            Some(tls_error) => MumbleClientError::Tls(tls_error.clone()),
+            None => MumbleClientError::Io(err)
+        }
+    }
+}
+
+impl From<rustls::Error> for MumbleClientError {
+    fn from(err: rustls::Error) -> Self {
+        MumbleClientError::Tls(err)
+    }
+}
@@ -3,11 +3,17 @@
 pub use crate::client::{
     raw_mumble_client::RawMumbleClient,
     // stateful_mumble_client::StatefulMumbleClient,
-    config::MumbleClientConfig
+    config::{MumbleClientConfig, ReconnectPolicy}
 };
+pub use crate::client_certificate::ClientCertificate;
+pub use crate::tls_configuration::{CertificateFingerprint, ServerCertificateVerification};
+
+pub use crate::error::MumbleClientError;
 
 pub use mumble_protocol_rs::control::ControlPacket;
 pub use mumble_protocol_rs::control::protobuf;
 
 pub mod tls_configuration;
+pub mod client_certificate;
+pub mod error;
 pub mod client;
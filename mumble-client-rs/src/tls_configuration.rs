@@ -1,19 +1,161 @@
 use std::error::Error;
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
 use tokio_rustls::rustls;
-use tokio_rustls::rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::rustls::{CertificateError, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme};
 use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use crate::MumbleClientError;
 
-pub fn create_root_certificate_store() -> Result<RootCertStore, Box<dyn Error>> {
+pub fn create_root_certificate_store() -> Result<RootCertStore, MumbleClientError> {
     let mut cert_store = RootCertStore::empty();
-    for cert in rustls_native_certs::load_native_certs().expect("Failed to load platform certificates") {
+    for cert in rustls_native_certs::load_native_certs()? {
         cert_store.add(cert)?;
     }
 
     Ok(cert_store)
 }
 
+/// Root store trusting only the PEM encoded CA certificates in the given file.
+pub fn create_ca_file_certificate_store(path: impl AsRef<Path>) -> Result<RootCertStore, MumbleClientError> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut cert_store = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut reader) {
+        cert_store.add(cert?)?;
+    }
+    if cert_store.is_empty() {
+        return Err(MumbleClientError::Certificate(format!("no CA certificate found in {}", path.display())));
+    }
+
+    Ok(cert_store)
+}
+
+/// How the certificate presented by the server is checked.
+#[derive(Clone, Debug, Default)]
+pub enum ServerCertificateVerification {
+    /// Trust certificates issued by the certificate authorities of the operating system.
+    #[default]
+    SystemRoots,
+    /// Trust certificates issued by the certificate authorities in the given PEM file.
+    CaFile(PathBuf),
+    /// Trust exactly the certificate with this fingerprint, e.g. a self-signed one.
+    Fingerprint(CertificateFingerprint),
+    /// Trust whichever certificate the server presents first and refuse any other one afterwards.
+    /// `known` is the fingerprint seen before, if any.
+    TrustOnFirstUse { known: Option<CertificateFingerprint> }
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CertificateFingerprint([u8; 32]);
+
+impl CertificateFingerprint {
+    pub fn of(certificate: &CertificateDer<'_>) -> Self {
+        Self(Sha256::digest(certificate.as_ref()).into())
+    }
+}
+
+/// Formats the fingerprint as colon separated upper case hex, the way browsers and `openssl x509 -fingerprint` show it.
+impl Display for CertificateFingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hex = self.0.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>();
+        write!(f, "{}", hex.join(":"))
+    }
+}
+
+/// Parses hex with or without colons, in either case.
+impl FromStr for CertificateFingerprint {
+    type Err = MumbleClientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MumbleClientError::Certificate(format!("invalid SHA-256 fingerprint: {}", s));
+        let hex = s.trim().replace(':', "");
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut fingerprint = [0u8; 32];
+        for (i, byte) in fingerprint.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(fingerprint))
+    }
+}
+
+/// The server presented a different certificate than the one that was trusted.
+#[derive(Debug)]
+pub struct FingerprintMismatch {
+    pub expected: CertificateFingerprint,
+    pub actual: CertificateFingerprint
+}
+
+impl Display for FingerprintMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server certificate fingerprint changed from {} to {}", self.expected, self.actual)
+    }
+}
+
+impl Error for FingerprintMismatch {}
+
+/// Accepts the server certificate only if it matches the expected fingerprint, or any certificate if
+/// none is expected yet. Handshake signatures are still checked against the certificate.
+pub struct FingerprintVerification {
+    expected: Option<CertificateFingerprint>,
+    crypto_provider: Arc<CryptoProvider>
+}
+
+impl FingerprintVerification {
+    pub fn new(expected: Option<CertificateFingerprint>, crypto_provider: Arc<CryptoProvider>) -> Self {
+        Self { expected, crypto_provider }
+    }
+}
+
+impl Debug for FingerprintVerification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.expected {
+            Some(expected) => write!(f, "Certificate Fingerprint Verification ({})", expected),
+            None => write!(f, "Certificate Fingerprint Verification (trust on first use)")
+        }
+    }
+}
+
+impl rustls::client::danger::ServerCertVerifier for FingerprintVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let actual = CertificateFingerprint::of(end_entity);
+        match self.expected {
+            Some(expected) if expected != actual => Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                OtherError(Arc::new(FingerprintMismatch { expected, actual }))))),
+            _ => Ok(rustls::client::danger::ServerCertVerified::assertion())
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.crypto_provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.crypto_provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.crypto_provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
 pub struct NoCertificateVerification {}
 
 impl Debug for NoCertificateVerification {
@@ -1,7 +1,7 @@
 use std::io::Result;
 
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["./protos/Mumble.proto"], &["./protos"])?;
+    prost_build::compile_protos(&["./protos/Mumble.proto", "./protos/MumbleUDP.proto"], &["./protos"])?;
 
     Ok(())
 }
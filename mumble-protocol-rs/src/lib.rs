@@ -3,3 +3,4 @@
 //! [Mumble]: https://mumble.info/
 
 pub mod control;
+pub mod voice;
@@ -0,0 +1,148 @@
+//! UDP voice channel: encryption and codecs for voice and ping datagrams
+
+pub mod crypt;
+pub mod packet;
+
+use std::io;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+
+pub use self::crypt::{CryptState, CryptStats, DecryptError};
+pub use self::packet::{AudioContext, AudioHeader, PacketOrigin, UdpPacket, VoicePacket, VoicePacketFormat, VoicePayload, VoicePing};
+
+/// A `Codec` implementation for use with `UdpFramed` which encrypts and decrypts whole datagrams.
+///
+/// Voice and ping packets share the same encryption, so items are the plain datagrams. A datagram which
+/// fails to decrypt is reported as an [io::ErrorKind::InvalidData] error and skipped, so the stream
+/// can be polled further.
+#[derive(Debug)]
+pub struct UdpCryptCodec {
+    crypt_state: CryptState,
+}
+
+impl UdpCryptCodec {
+    /// Creates a new codec encrypting with the given state.
+    pub fn new(crypt_state: CryptState) -> Self {
+        UdpCryptCodec { crypt_state }
+    }
+
+    /// The encryption state, e.g. to resync it or read its statistics.
+    pub fn crypt_state(&self) -> &CryptState {
+        &self.crypt_state
+    }
+
+    /// Mutable access to the encryption state.
+    pub fn crypt_state_mut(&mut self) -> &mut CryptState {
+        &mut self.crypt_state
+    }
+}
+
+impl UdpCryptCodec {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, io::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        // Each datagram is decoded as a whole, also when it fails to decrypt
+        let datagram = src.split();
+        Ok(Some(self.crypt_state.decrypt(&datagram)?.into()))
+    }
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.crypt_state.encrypt(&item, dst);
+        Ok(())
+    }
+}
+
+impl tokio_util::codec::Decoder for UdpCryptCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(src)
+    }
+}
+
+impl tokio_util::codec::Encoder<Bytes> for UdpCryptCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(item, dst)
+    }
+}
+
+/// A `Codec` implementation for use with `UdpFramed` which parses encrypted datagrams into [UdpPacket]s.
+#[derive(Debug)]
+pub struct VoiceCodec {
+    inner: UdpCryptCodec,
+    format: VoicePacketFormat,
+    local_origin: PacketOrigin,
+}
+
+impl VoiceCodec {
+    /// Creates the codec of a client, which decodes packets sent by the server.
+    pub fn client(crypt_state: CryptState, format: VoicePacketFormat) -> Self {
+        VoiceCodec { inner: UdpCryptCodec::new(crypt_state), format, local_origin: PacketOrigin::Client }
+    }
+
+    /// Creates the codec of a server, which decodes packets sent by a client.
+    pub fn server(crypt_state: CryptState, format: VoicePacketFormat) -> Self {
+        VoiceCodec { inner: UdpCryptCodec::new(crypt_state), format, local_origin: PacketOrigin::Server }
+    }
+
+    pub fn format(&self) -> VoicePacketFormat {
+        self.format
+    }
+
+    /// Switches the format, e.g. once the versions of both sides are known.
+    pub fn set_format(&mut self, format: VoicePacketFormat) {
+        self.format = format;
+    }
+
+    pub fn crypt_state(&self) -> &CryptState {
+        self.inner.crypt_state()
+    }
+
+    pub fn crypt_state_mut(&mut self) -> &mut CryptState {
+        self.inner.crypt_state_mut()
+    }
+
+    fn remote_origin(&self) -> PacketOrigin {
+        match self.local_origin {
+            PacketOrigin::Client => PacketOrigin::Server,
+            PacketOrigin::Server => PacketOrigin::Client,
+        }
+    }
+}
+
+impl VoiceCodec {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<UdpPacket>, io::Error> {
+        match self.inner.decode(src)? {
+            Some(plain) => Ok(Some(UdpPacket::decode(&plain, self.format, self.remote_origin())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, item: UdpPacket, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let mut plain = BytesMut::new();
+        item.encode(self.format, &mut plain)?;
+        self.inner.encode(plain.freeze(), dst)
+    }
+}
+
+impl tokio_util::codec::Decoder for VoiceCodec {
+    type Item = UdpPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(src)
+    }
+}
+
+impl tokio_util::codec::Encoder<UdpPacket> for VoiceCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: UdpPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(item, dst)
+    }
+}
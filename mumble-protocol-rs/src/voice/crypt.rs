@@ -0,0 +1,545 @@
+//! OCB2-AES128 encryption of UDP packets, compatible with Mumble's `CryptStateOCB2`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::time::Instant;
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Block};
+use bytes::BufMut;
+use bytes::BytesMut;
+
+use crate::control::protobuf;
+
+/// Size of the AES key and of the nonces.
+pub const KEY_SIZE: usize = 16;
+/// Bytes an encrypted packet is longer than its plain text: one nonce byte and three tag bytes.
+pub const HEADER_SIZE: usize = 4;
+
+const BLOCK_SIZE: usize = 16;
+
+/// Why a packet could not be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecryptError {
+    /// The packet is too short to be encrypted.
+    Eof,
+    /// The packet was already received.
+    Repeat,
+    /// The packet is too far out of order to tell which nonce it was encrypted with.
+    Late,
+    /// The authentication tag does not match, the packet was tampered with or the nonces are out of sync.
+    Mac,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::Eof => write!(f, "packet too short"),
+            DecryptError::Repeat => write!(f, "packet was repeated"),
+            DecryptError::Late => write!(f, "packet arrived too late"),
+            DecryptError::Mac => write!(f, "packet failed authentication"),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+impl From<DecryptError> for io::Error {
+    fn from(err: DecryptError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Packet statistics of a [CryptState], as reported to the server in `Ping` messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CryptStats {
+    /// Packets decrypted successfully.
+    pub good: u32,
+    /// Packets which arrived out of order.
+    pub late: u32,
+    /// Packets which never arrived.
+    pub lost: u32,
+    /// Times the decrypt nonce was resynchronised by the server.
+    pub resync: u32,
+}
+
+/// Encryption state of a UDP connection. Each side encrypts with its own nonce, which is incremented for
+/// every packet, and tracks the nonce of the other side to decrypt late and out of order packets.
+pub struct CryptState {
+    key: [u8; KEY_SIZE],
+    cipher: Aes128,
+    encrypt_nonce: [u8; KEY_SIZE],
+    decrypt_nonce: [u8; KEY_SIZE],
+    decrypt_history: [u8; 256],
+    stats: CryptStats,
+    last_good: Option<Instant>,
+}
+
+impl CryptState {
+    /// Creates the state from the key and nonces of a `CryptSetup` message. A client encrypts with the
+    /// client nonce and decrypts with the server nonce.
+    pub fn new(key: [u8; KEY_SIZE], encrypt_nonce: [u8; KEY_SIZE], decrypt_nonce: [u8; KEY_SIZE]) -> Self {
+        CryptState {
+            key,
+            cipher: Aes128::new(&key.into()),
+            encrypt_nonce,
+            decrypt_nonce,
+            decrypt_history: [0; 256],
+            stats: CryptStats::default(),
+            last_good: None,
+        }
+    }
+
+    /// The key shared by both sides.
+    pub fn key(&self) -> &[u8; KEY_SIZE] {
+        &self.key
+    }
+
+    /// The nonce the next packet is encrypted with, sent to the server when it asks for a resync.
+    pub fn encrypt_nonce(&self) -> &[u8; KEY_SIZE] {
+        &self.encrypt_nonce
+    }
+
+    /// The nonce of the last packet received in order.
+    pub fn decrypt_nonce(&self) -> &[u8; KEY_SIZE] {
+        &self.decrypt_nonce
+    }
+
+    /// Replaces the decrypt nonce with the one the other side resynchronised to.
+    pub fn set_decrypt_nonce(&mut self, nonce: [u8; KEY_SIZE]) {
+        self.decrypt_nonce = nonce;
+        self.stats.resync += 1;
+    }
+
+    pub fn stats(&self) -> CryptStats {
+        self.stats
+    }
+
+    /// When the last packet was decrypted successfully. Mumble clients ask the server to resync with an
+    /// empty `CryptSetup` once no packet could be decrypted for a few seconds.
+    pub fn last_good(&self) -> Option<Instant> {
+        self.last_good
+    }
+
+    /// Applies a `CryptSetup` message received from the server on an established connection. A message
+    /// carrying only the server nonce resynchronises the decrypt nonce, an empty one asks for our encrypt
+    /// nonce, which is returned as the response to send back.
+    pub fn handle_crypt_setup(&mut self, msg: &protobuf::CryptSetup) -> Result<Option<protobuf::CryptSetup>, io::Error> {
+        match &msg.server_nonce {
+            Some(server_nonce) => {
+                self.set_decrypt_nonce(to_array(server_nonce, "server nonce")?);
+                Ok(None)
+            }
+            None => Ok(Some(protobuf::CryptSetup {
+                client_nonce: Some(self.encrypt_nonce.to_vec()),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Encrypts a packet with the next nonce and appends it to `dst`.
+    pub fn encrypt(&mut self, plain: &[u8], dst: &mut BytesMut) {
+        increment(&mut self.encrypt_nonce);
+
+        let mut encrypted = vec![0; plain.len()];
+        // Flipping a bit of an all zero block is harmless for audio and avoids the XEX* attack
+        let (tag, _) = self.ocb_encrypt(plain, &mut encrypted, &self.encrypt_nonce, true);
+
+        dst.reserve(HEADER_SIZE + plain.len());
+        dst.put_u8(self.encrypt_nonce[0]);
+        dst.put_slice(&tag[..3]);
+        dst.put_slice(&encrypted);
+    }
+
+    /// Decrypts a packet, updating the decrypt nonce and the packet statistics.
+    pub fn decrypt(&mut self, packet: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(DecryptError::Eof);
+        }
+        let nonce_byte = packet[0];
+        let saved_nonce = self.decrypt_nonce;
+        let mut restore = false;
+        let mut late = 0;
+        let mut lost = 0;
+
+        if self.decrypt_nonce[0].wrapping_add(1) == nonce_byte {
+            // In order as expected
+            if nonce_byte > self.decrypt_nonce[0] {
+                self.decrypt_nonce[0] = nonce_byte;
+            } else if nonce_byte < self.decrypt_nonce[0] {
+                self.decrypt_nonce[0] = nonce_byte;
+                increment(&mut self.decrypt_nonce[1..]);
+            } else {
+                return Err(DecryptError::Repeat);
+            }
+        } else {
+            // Either out of order or a repeat
+            let mut diff = i32::from(nonce_byte) - i32::from(self.decrypt_nonce[0]);
+            if diff > 128 {
+                diff -= 256;
+            } else if diff < -128 {
+                diff += 256;
+            }
+
+            if nonce_byte < self.decrypt_nonce[0] && diff > -30 && diff < 0 {
+                // Late packet, but no wraparound
+                late = 1;
+                lost = -1;
+                self.decrypt_nonce[0] = nonce_byte;
+                restore = true;
+            } else if nonce_byte > self.decrypt_nonce[0] && diff > -30 && diff < 0 {
+                // Late packet from before the last wraparound
+                late = 1;
+                lost = -1;
+                self.decrypt_nonce[0] = nonce_byte;
+                decrement(&mut self.decrypt_nonce[1..]);
+                restore = true;
+            } else if nonce_byte > self.decrypt_nonce[0] && diff > 0 {
+                // Lost a few packets
+                lost = i32::from(nonce_byte) - i32::from(self.decrypt_nonce[0]) - 1;
+                self.decrypt_nonce[0] = nonce_byte;
+            } else if nonce_byte < self.decrypt_nonce[0] && diff > 0 {
+                // Lost a few packets and wrapped around
+                lost = 256 - i32::from(self.decrypt_nonce[0]) + i32::from(nonce_byte) - 1;
+                self.decrypt_nonce[0] = nonce_byte;
+                increment(&mut self.decrypt_nonce[1..]);
+            } else if diff == 0 {
+                return Err(DecryptError::Repeat);
+            } else {
+                return Err(DecryptError::Late);
+            }
+
+            if self.decrypt_history[usize::from(self.decrypt_nonce[0])] == self.decrypt_nonce[1] {
+                self.decrypt_nonce = saved_nonce;
+                return Err(DecryptError::Repeat);
+            }
+        }
+
+        let mut plain = vec![0; packet.len() - HEADER_SIZE];
+        let (tag, success) = self.ocb_decrypt(&packet[HEADER_SIZE..], &mut plain, &self.decrypt_nonce);
+        if !success || tag[..3] != packet[1..HEADER_SIZE] {
+            self.decrypt_nonce = saved_nonce;
+            return Err(DecryptError::Mac);
+        }
+
+        self.decrypt_history[usize::from(self.decrypt_nonce[0])] = self.decrypt_nonce[1];
+        if restore {
+            self.decrypt_nonce = saved_nonce;
+        }
+
+        self.stats.good += 1;
+        self.stats.late = self.stats.late.saturating_add_signed(late);
+        self.stats.lost = self.stats.lost.saturating_add_signed(lost);
+        self.last_good = Some(Instant::now());
+
+        Ok(plain)
+    }
+
+    /// Returns the tag and whether the plain text was free of blocks enabling the XEX* attack, which are
+    /// modified instead of rejected if `modify_plain_on_xex_star_attack` is set.
+    fn ocb_encrypt(&self, plain: &[u8], encrypted: &mut [u8], nonce: &[u8; KEY_SIZE], modify_plain_on_xex_star_attack: bool) -> ([u8; BLOCK_SIZE], bool) {
+        let mut success = true;
+        let mut checksum = [0; BLOCK_SIZE];
+        let mut delta = *nonce;
+        self.encrypt_block(&mut delta);
+
+        let mut offset = 0;
+        let mut len = plain.len();
+        while len > BLOCK_SIZE {
+            let plain_block = &plain[offset..offset + BLOCK_SIZE];
+            let mut flip_a_bit = false;
+            // Counter-cryptanalysis described in section 9 of https://eprint.iacr.org/2019/311
+            if len - BLOCK_SIZE <= BLOCK_SIZE && plain_block[..BLOCK_SIZE - 1].iter().all(|&byte| byte == 0) {
+                if modify_plain_on_xex_star_attack {
+                    flip_a_bit = true;
+                } else {
+                    success = false;
+                }
+            }
+
+            delta = s2(delta);
+            let mut tmp = xor(&delta, plain_block);
+            if flip_a_bit {
+                tmp[0] ^= 1;
+            }
+            self.encrypt_block(&mut tmp);
+            encrypted[offset..offset + BLOCK_SIZE].copy_from_slice(&xor(&delta, &tmp));
+            checksum = xor(&checksum, plain_block);
+            if flip_a_bit {
+                checksum[0] ^= 1;
+            }
+
+            len -= BLOCK_SIZE;
+            offset += BLOCK_SIZE;
+        }
+
+        delta = s2(delta);
+        let mut pad = length_block(len);
+        pad = xor(&pad, &delta);
+        self.encrypt_block(&mut pad);
+
+        let mut tmp = pad;
+        tmp[..len].copy_from_slice(&plain[offset..]);
+        checksum = xor(&checksum, &tmp);
+        let tmp = xor(&pad, &tmp);
+        encrypted[offset..].copy_from_slice(&tmp[..len]);
+
+        let mut tag = xor(&s3(delta), &checksum);
+        self.encrypt_block(&mut tag);
+
+        (tag, success)
+    }
+
+    /// Returns the tag and whether the decrypted plain text could be the result of the XEX* attack.
+    fn ocb_decrypt(&self, encrypted: &[u8], plain: &mut [u8], nonce: &[u8; KEY_SIZE]) -> ([u8; BLOCK_SIZE], bool) {
+        let mut success = true;
+        let mut checksum = [0; BLOCK_SIZE];
+        let mut delta = *nonce;
+        self.encrypt_block(&mut delta);
+
+        let mut offset = 0;
+        let mut len = encrypted.len();
+        while len > BLOCK_SIZE {
+            delta = s2(delta);
+            let mut tmp = xor(&delta, &encrypted[offset..offset + BLOCK_SIZE]);
+            self.decrypt_block(&mut tmp);
+            let plain_block = xor(&delta, &tmp);
+            plain[offset..offset + BLOCK_SIZE].copy_from_slice(&plain_block);
+            checksum = xor(&checksum, &plain_block);
+
+            len -= BLOCK_SIZE;
+            offset += BLOCK_SIZE;
+        }
+
+        delta = s2(delta);
+        let mut pad = length_block(len);
+        pad = xor(&pad, &delta);
+        self.encrypt_block(&mut pad);
+
+        let mut tmp = [0; BLOCK_SIZE];
+        tmp[..len].copy_from_slice(&encrypted[offset..]);
+        let tmp = xor(&tmp, &pad);
+        checksum = xor(&checksum, &tmp);
+        plain[offset..].copy_from_slice(&tmp[..len]);
+
+        // An attacker would need the last block to decrypt to `delta ^ len`, and `len` only ever fills the last byte
+        if tmp[..BLOCK_SIZE - 1] == delta[..BLOCK_SIZE - 1] {
+            success = false;
+        }
+
+        let mut tag = xor(&s3(delta), &checksum);
+        self.encrypt_block(&mut tag);
+
+        (tag, success)
+    }
+
+    fn encrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        self.cipher.encrypt_block(Block::from_mut_slice(block));
+    }
+
+    fn decrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        self.cipher.decrypt_block(Block::from_mut_slice(block));
+    }
+}
+
+impl TryFrom<&protobuf::CryptSetup> for CryptState {
+    type Error = io::Error;
+
+    /// Creates the client side state from the initial `CryptSetup` message, which carries all three values.
+    fn try_from(msg: &protobuf::CryptSetup) -> Result<Self, Self::Error> {
+        let key = to_array(msg.key(), "key")?;
+        let client_nonce = to_array(msg.client_nonce(), "client nonce")?;
+        let server_nonce = to_array(msg.server_nonce(), "server nonce")?;
+        Ok(CryptState::new(key, client_nonce, server_nonce))
+    }
+}
+
+impl fmt::Debug for CryptState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptState").field("stats", &self.stats).finish_non_exhaustive()
+    }
+}
+
+fn to_array(bytes: &[u8], name: &str) -> Result<[u8; KEY_SIZE], io::Error> {
+    <[u8; KEY_SIZE]>::try_from(bytes).map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("CryptSetup {} must be {} bytes, got {}", name, KEY_SIZE, bytes.len())))
+}
+
+/// Increments a little endian counter.
+fn increment(counter: &mut [u8]) {
+    for byte in counter {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+/// Decrements a little endian counter.
+fn decrement(counter: &mut [u8]) {
+    for byte in counter {
+        let previous = *byte;
+        *byte = byte.wrapping_sub(1);
+        if previous != 0 {
+            break;
+        }
+    }
+}
+
+fn xor(a: &[u8; BLOCK_SIZE], b: &[u8]) -> [u8; BLOCK_SIZE] {
+    let mut result = *a;
+    for (result, b) in result.iter_mut().zip(b) {
+        *result ^= b;
+    }
+    result
+}
+
+/// Doubling in GF(2^128).
+fn s2(block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    let value = u128::from_be_bytes(block);
+    let carry = if value >> 127 == 1 { 0x87 } else { 0 };
+    ((value << 1) ^ carry).to_be_bytes()
+}
+
+/// Tripling in GF(2^128).
+fn s3(block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    xor(&block, &s2(block))
+}
+
+/// Block holding the bit length of the final, possibly partial, block.
+fn length_block(len: usize) -> [u8; BLOCK_SIZE] {
+    let mut block = [0; BLOCK_SIZE];
+    block[BLOCK_SIZE - 4..].copy_from_slice(&((len * 8) as u32).to_be_bytes());
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(len: u8) -> Vec<u8> {
+        (0..len).collect()
+    }
+
+    fn pair() -> (CryptState, CryptState) {
+        let key = [0x55; KEY_SIZE];
+        let client_nonce = [0x33; KEY_SIZE];
+        let server_nonce = [0x99; KEY_SIZE];
+        (CryptState::new(key, client_nonce, server_nonce), CryptState::new(key, server_nonce, client_nonce))
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn known_answer_empty() {
+        let nonce: [u8; KEY_SIZE] = sequence(16).try_into().unwrap();
+        let crypt = CryptState::new(nonce, nonce, nonce);
+
+        let (tag, success) = crypt.ocb_encrypt(&[], &mut [], &nonce, false);
+
+        assert!(success);
+        assert_eq!(tag.to_vec(), from_hex("BF3108130773AD5EC70EC69E7875A7B0"));
+    }
+
+    #[test]
+    fn known_answer_40_bytes() {
+        let nonce: [u8; KEY_SIZE] = sequence(16).try_into().unwrap();
+        let crypt = CryptState::new(nonce, nonce, nonce);
+        let plain = sequence(40);
+        let mut encrypted = vec![0; plain.len()];
+
+        let (tag, success) = crypt.ocb_encrypt(&plain, &mut encrypted, &nonce, false);
+
+        assert!(success);
+        assert_eq!(encrypted, from_hex("F75D6BC8B4DC8D66B836A2B08B32A6369F1CD3C5228D79FD6C267F5F6AA7B231C7DFB9D59951AE9C"));
+        assert_eq!(tag.to_vec(), from_hex("9DB0CDF880F73E3E10D4EB3217766688"));
+
+        let mut decrypted = vec![0; encrypted.len()];
+        let (decrypt_tag, success) = crypt.ocb_decrypt(&encrypted, &mut decrypted, &nonce);
+        assert!(success);
+        assert_eq!(decrypt_tag, tag);
+        assert_eq!(decrypted, plain);
+    }
+
+    #[test]
+    fn round_trip_all_lengths() {
+        let (mut client, mut server) = pair();
+        for len in 0..128 {
+            let plain = sequence(len);
+            let mut packet = BytesMut::new();
+            client.encrypt(&plain, &mut packet);
+            assert_eq!(packet.len(), plain.len() + HEADER_SIZE);
+            assert_eq!(server.decrypt(&packet), Ok(plain));
+        }
+        assert_eq!(server.stats(), CryptStats { good: 128, ..Default::default() });
+    }
+
+    #[test]
+    fn rejects_tampered_packets() {
+        let (mut client, mut server) = pair();
+        let mut packet = BytesMut::new();
+        client.encrypt(&sequence(20), &mut packet);
+
+        for i in 0..packet.len() {
+            let mut tampered = packet.to_vec();
+            tampered[i] ^= 0x01;
+            assert!(server.decrypt(&tampered).is_err());
+        }
+        assert_eq!(server.decrypt(&packet), Ok(sequence(20)));
+        assert_eq!(server.decrypt(&packet), Err(DecryptError::Repeat));
+        assert_eq!(server.decrypt(&packet[..3]), Err(DecryptError::Eof));
+    }
+
+    #[test]
+    fn counts_lost_and_late_packets_across_wraparound() {
+        let (mut client, mut server) = pair();
+        let packets = (0..600).map(|_| {
+            let mut packet = BytesMut::new();
+            client.encrypt(&sequence(10), &mut packet);
+            packet
+        }).collect::<Vec<_>>();
+
+        // Every second packet in reverse order within each pair
+        for pair in packets.chunks(2) {
+            assert_eq!(server.decrypt(&pair[1]), Ok(sequence(10)));
+            assert_eq!(server.decrypt(&pair[0]), Ok(sequence(10)));
+        }
+        assert_eq!(server.stats(), CryptStats { good: 600, late: 300, lost: 0, resync: 0 });
+
+        // Skipping packets, including across the nonce wrapping around
+        let packets = (0..300).map(|_| {
+            let mut packet = BytesMut::new();
+            client.encrypt(&sequence(10), &mut packet);
+            packet
+        }).collect::<Vec<_>>();
+        for packet in packets.iter().step_by(3) {
+            assert_eq!(server.decrypt(packet), Ok(sequence(10)));
+        }
+        assert_eq!(server.stats().lost, 198);
+    }
+
+    #[test]
+    fn recovers_after_resync() {
+        let (mut client, mut server) = pair();
+        let mut packet = BytesMut::new();
+        for _ in 0..200 {
+            packet.clear();
+            client.encrypt(&sequence(10), &mut packet);
+        }
+        // Too far ahead of the last packet the server saw
+        assert_eq!(server.decrypt(&packet), Err(DecryptError::Late));
+
+        // The server asks for the client's nonce with an empty CryptSetup
+        let response = client.handle_crypt_setup(&protobuf::CryptSetup::default()).unwrap().unwrap();
+        server.set_decrypt_nonce(response.client_nonce().try_into().unwrap());
+
+        packet.clear();
+        client.encrypt(&sequence(10), &mut packet);
+        assert_eq!(server.decrypt(&packet), Ok(sequence(10)));
+        assert_eq!(server.stats().resync, 1);
+    }
+}
@@ -0,0 +1,554 @@
+//! Voice and ping packets in both the legacy and the protobuf (Mumble 1.5+) UDP format
+
+use std::io;
+
+use bytes::Buf;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+use prost::Message;
+
+/// ProtoBuf messages of the UDP format introduced with Mumble 1.5.
+#[allow(missing_docs)] // these would have to be auto-generated by protobuf
+pub mod protobuf {
+    include!(concat!(env!("OUT_DIR"), "/mumble_udp.rs"));
+}
+
+/// Legacy `version` of Mumble 1.5.0, the first release supporting the protobuf UDP format.
+pub const PROTOBUF_FORMAT_MIN_VERSION: u32 = (1 << 16) | (5 << 8);
+
+/// Voice target of normal talking to the current channel.
+pub const TARGET_NORMAL: u32 = 0;
+/// Voice target asking the server to send the audio back to the client.
+pub const TARGET_SERVER_LOOPBACK: u32 = 31;
+
+const LEGACY_TYPE_CELT_ALPHA: u8 = 0;
+const LEGACY_TYPE_PING: u8 = 1;
+const LEGACY_TYPE_SPEEX: u8 = 2;
+const LEGACY_TYPE_CELT_BETA: u8 = 3;
+const LEGACY_TYPE_OPUS: u8 = 4;
+
+const PROTOBUF_TYPE_AUDIO: u8 = 0;
+const PROTOBUF_TYPE_PING: u8 = 1;
+
+const OPUS_TERMINATOR_BIT: u64 = 0x2000;
+const OPUS_MAX_LENGTH: usize = 0x1fff;
+
+/// Wire format of voice packets, both over UDP and tunnelled through `UDPTunnel` control packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum VoicePacketFormat {
+    /// Varint based format of Mumble before 1.5.
+    #[default]
+    Legacy,
+    /// `MumbleUDP.proto` messages, used once both sides run Mumble 1.5 or newer.
+    Protobuf,
+}
+
+impl VoicePacketFormat {
+    /// The format to use given the legacy `version` fields both sides sent in their `Version` messages.
+    pub fn negotiate(client_version: u32, server_version: u32) -> Self {
+        if client_version >= PROTOBUF_FORMAT_MIN_VERSION && server_version >= PROTOBUF_FORMAT_MIN_VERSION {
+            VoicePacketFormat::Protobuf
+        } else {
+            VoicePacketFormat::Legacy
+        }
+    }
+}
+
+/// Which side of the connection sent a packet. The legacy format only tells apart voice targets and
+/// contexts, and whether the sender session is included, by the direction a packet travels in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketOrigin {
+    Client,
+    Server,
+}
+
+/// A packet sent over the UDP voice channel.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UdpPacket {
+    Voice(VoicePacket),
+    Ping(VoicePing),
+}
+
+/// Audio sent by a client, or relayed to a client by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoicePacket {
+    pub header: AudioHeader,
+    /// Session of the user talking, only set in packets sent by the server.
+    pub session_id: Option<u32>,
+    /// Number of the first audio frame in the packet, counting up during a transmission.
+    pub frame_number: u64,
+    pub payload: VoicePayload,
+    /// X, Y and Z position of the speaker in a game world, in meters.
+    pub position: Option<[f32; 3]>,
+    /// Volume adjustment the server suggests for this packet, only sent in the protobuf format.
+    pub volume_adjustment: Option<f32>,
+}
+
+/// The voice target a client talks to, or the context in which the server relays audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioHeader {
+    /// Sent by the client, [TARGET_NORMAL], a target registered with a `VoiceTarget` message, or
+    /// [TARGET_SERVER_LOOPBACK].
+    Target(u32),
+    /// Sent by the server.
+    Context(AudioContext),
+}
+
+/// Why a client receives audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioContext {
+    Normal,
+    /// Shouted to the channel or one of its linked channels.
+    Shout,
+    Whisper,
+    /// Received through a channel listener, only reported in the protobuf format.
+    Listen,
+    /// A context this crate doesn't know yet.
+    Other(u32),
+}
+
+impl From<u32> for AudioContext {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => AudioContext::Normal,
+            1 => AudioContext::Shout,
+            2 => AudioContext::Whisper,
+            3 => AudioContext::Listen,
+            other => AudioContext::Other(other),
+        }
+    }
+}
+
+impl From<AudioContext> for u32 {
+    fn from(context: AudioContext) -> Self {
+        match context {
+            AudioContext::Normal => 0,
+            AudioContext::Shout => 1,
+            AudioContext::Whisper => 2,
+            AudioContext::Listen => 3,
+            AudioContext::Other(other) => other,
+        }
+    }
+}
+
+/// Encoded audio frames. Only Opus is supported by the protobuf format and current Mumble releases.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VoicePayload {
+    /// CELT 0.7.0 frames, an empty frame terminates the transmission.
+    CeltAlpha(Vec<Bytes>),
+    /// Speex frames, an empty frame terminates the transmission.
+    Speex(Vec<Bytes>),
+    /// CELT 0.11.0 frames, an empty frame terminates the transmission.
+    CeltBeta(Vec<Bytes>),
+    /// Opus data, `terminator` marks the last packet of a transmission.
+    Opus { data: Bytes, terminator: bool },
+}
+
+/// UDP ping, answered by the server with the same timestamp.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct VoicePing {
+    /// Chosen by the client, usually the time the ping was sent.
+    pub timestamp: u64,
+    /// Asks the server to fill in the fields below, protobuf format only.
+    pub request_extended_information: bool,
+    pub server_version_v2: u64,
+    pub user_count: u32,
+    pub max_user_count: u32,
+    pub max_bandwidth_per_user: u32,
+}
+
+impl UdpPacket {
+    /// Encodes the packet without encryption, as done for `UDPTunnel` packets.
+    pub fn encode(&self, format: VoicePacketFormat, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match format {
+            VoicePacketFormat::Legacy => self.encode_legacy(dst),
+            VoicePacketFormat::Protobuf => self.encode_protobuf(dst),
+        }
+    }
+
+    /// Decodes an unencrypted packet, as received in `UDPTunnel` packets.
+    pub fn decode(mut buf: &[u8], format: VoicePacketFormat, origin: PacketOrigin) -> Result<Self, io::Error> {
+        match format {
+            VoicePacketFormat::Legacy => Self::decode_legacy(&mut buf, origin),
+            VoicePacketFormat::Protobuf => Self::decode_protobuf(buf, origin),
+        }
+    }
+
+    fn encode_legacy(&self, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match self {
+            UdpPacket::Ping(ping) => {
+                dst.put_u8(LEGACY_TYPE_PING << 5);
+                write_varint(dst, ping.timestamp);
+            }
+            UdpPacket::Voice(voice) => {
+                let (kind, session_id) = match voice.header {
+                    AudioHeader::Target(target) => (target, None),
+                    AudioHeader::Context(context) => (context.into(), Some(voice.session_id.unwrap_or_default())),
+                };
+                if kind > 0x1f {
+                    return Err(invalid_data("voice target or context does not fit the legacy format"));
+                }
+                let type_id = match &voice.payload {
+                    VoicePayload::CeltAlpha(_) => LEGACY_TYPE_CELT_ALPHA,
+                    VoicePayload::Speex(_) => LEGACY_TYPE_SPEEX,
+                    VoicePayload::CeltBeta(_) => LEGACY_TYPE_CELT_BETA,
+                    VoicePayload::Opus { .. } => LEGACY_TYPE_OPUS,
+                };
+
+                dst.put_u8((type_id << 5) | kind as u8);
+                if let Some(session_id) = session_id {
+                    write_varint(dst, session_id.into());
+                }
+                write_varint(dst, voice.frame_number);
+                match &voice.payload {
+                    VoicePayload::CeltAlpha(frames) | VoicePayload::Speex(frames) | VoicePayload::CeltBeta(frames) => {
+                        for (i, frame) in frames.iter().enumerate() {
+                            if frame.len() > 0x7f {
+                                return Err(invalid_data("audio frame too long"));
+                            }
+                            let continuation = if i + 1 < frames.len() { 0x80 } else { 0 };
+                            dst.put_u8(continuation | frame.len() as u8);
+                            dst.put_slice(frame);
+                        }
+                    }
+                    VoicePayload::Opus { data, terminator } => {
+                        if data.len() > OPUS_MAX_LENGTH {
+                            return Err(invalid_data("opus data too long"));
+                        }
+                        let terminator_bit = if *terminator { OPUS_TERMINATOR_BIT } else { 0 };
+                        write_varint(dst, data.len() as u64 | terminator_bit);
+                        dst.put_slice(data);
+                    }
+                }
+                if let Some(position) = voice.position {
+                    // Mumble's PacketDataStream copies the float's bytes as they are in memory, which is
+                    // little endian on every platform Mumble runs on
+                    for coordinate in position {
+                        dst.put_f32_le(coordinate);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_legacy(buf: &mut &[u8], origin: PacketOrigin) -> Result<Self, io::Error> {
+        if !buf.has_remaining() {
+            return Err(invalid_data("empty voice packet"));
+        }
+        let header = buf.get_u8();
+        let type_id = header >> 5;
+        let kind = u32::from(header & 0x1f);
+
+        if type_id == LEGACY_TYPE_PING {
+            return Ok(UdpPacket::Ping(VoicePing {
+                timestamp: read_varint(buf)?,
+                ..Default::default()
+            }));
+        }
+
+        let (header, session_id) = match origin {
+            PacketOrigin::Client => (AudioHeader::Target(kind), None),
+            PacketOrigin::Server => (AudioHeader::Context(kind.into()), Some(read_varint(buf)? as u32)),
+        };
+        let frame_number = read_varint(buf)?;
+        let payload = match type_id {
+            LEGACY_TYPE_OPUS => {
+                let opus_header = read_varint(buf)?;
+                let len = (opus_header & OPUS_MAX_LENGTH as u64) as usize;
+                if buf.remaining() < len {
+                    return Err(invalid_data("opus data truncated"));
+                }
+                VoicePayload::Opus {
+                    data: buf.copy_to_bytes(len),
+                    terminator: opus_header & OPUS_TERMINATOR_BIT != 0,
+                }
+            }
+            LEGACY_TYPE_CELT_ALPHA | LEGACY_TYPE_SPEEX | LEGACY_TYPE_CELT_BETA => {
+                let mut frames = Vec::new();
+                loop {
+                    if !buf.has_remaining() {
+                        return Err(invalid_data("audio frames truncated"));
+                    }
+                    let frame_header = buf.get_u8();
+                    let len = usize::from(frame_header & 0x7f);
+                    if buf.remaining() < len {
+                        return Err(invalid_data("audio frame truncated"));
+                    }
+                    frames.push(buf.copy_to_bytes(len));
+                    if frame_header & 0x80 == 0 {
+                        break;
+                    }
+                }
+                match type_id {
+                    LEGACY_TYPE_CELT_ALPHA => VoicePayload::CeltAlpha(frames),
+                    LEGACY_TYPE_SPEEX => VoicePayload::Speex(frames),
+                    _ => VoicePayload::CeltBeta(frames),
+                }
+            }
+            _ => return Err(invalid_data("unknown voice packet type")),
+        };
+        let position = if buf.remaining() >= 12 {
+            Some([buf.get_f32_le(), buf.get_f32_le(), buf.get_f32_le()])
+        } else {
+            None
+        };
+
+        Ok(UdpPacket::Voice(VoicePacket {
+            header,
+            session_id,
+            frame_number,
+            payload,
+            position,
+            volume_adjustment: None,
+        }))
+    }
+
+    fn encode_protobuf(&self, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match self {
+            UdpPacket::Ping(ping) => {
+                let msg = protobuf::Ping {
+                    timestamp: ping.timestamp,
+                    request_extended_information: ping.request_extended_information,
+                    server_version_v2: ping.server_version_v2,
+                    user_count: ping.user_count,
+                    max_user_count: ping.max_user_count,
+                    max_bandwidth_per_user: ping.max_bandwidth_per_user,
+                };
+                dst.reserve(1 + msg.encoded_len());
+                dst.put_u8(PROTOBUF_TYPE_PING);
+                msg.encode(dst)?;
+            }
+            UdpPacket::Voice(voice) => {
+                let VoicePayload::Opus { data, terminator } = &voice.payload else {
+                    return Err(invalid_data("the protobuf format only supports opus"));
+                };
+                let header = match voice.header {
+                    AudioHeader::Target(target) => protobuf::audio::Header::Target(target),
+                    AudioHeader::Context(context) => protobuf::audio::Header::Context(context.into()),
+                };
+                let msg = protobuf::Audio {
+                    header: Some(header),
+                    sender_session: voice.session_id.unwrap_or_default(),
+                    frame_number: voice.frame_number,
+                    opus_data: data.to_vec(),
+                    positional_data: voice.position.map(Vec::from).unwrap_or_default(),
+                    volume_adjustment: voice.volume_adjustment.unwrap_or_default(),
+                    is_terminator: *terminator,
+                };
+                dst.reserve(1 + msg.encoded_len());
+                dst.put_u8(PROTOBUF_TYPE_AUDIO);
+                msg.encode(dst)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_protobuf(buf: &[u8], origin: PacketOrigin) -> Result<Self, io::Error> {
+        let Some((&type_id, msg)) = buf.split_first() else {
+            return Err(invalid_data("empty voice packet"));
+        };
+        match type_id {
+            PROTOBUF_TYPE_PING => {
+                let ping = protobuf::Ping::decode(msg)?;
+                Ok(UdpPacket::Ping(VoicePing {
+                    timestamp: ping.timestamp,
+                    request_extended_information: ping.request_extended_information,
+                    server_version_v2: ping.server_version_v2,
+                    user_count: ping.user_count,
+                    max_user_count: ping.max_user_count,
+                    max_bandwidth_per_user: ping.max_bandwidth_per_user,
+                }))
+            }
+            PROTOBUF_TYPE_AUDIO => {
+                let audio = protobuf::Audio::decode(msg)?;
+                let header = match (audio.header, origin) {
+                    (Some(protobuf::audio::Header::Target(target)), _) => AudioHeader::Target(target),
+                    (Some(protobuf::audio::Header::Context(context)), _) => AudioHeader::Context(context.into()),
+                    // Zero values are not encoded in proto3
+                    (None, PacketOrigin::Client) => AudioHeader::Target(TARGET_NORMAL),
+                    (None, PacketOrigin::Server) => AudioHeader::Context(AudioContext::Normal),
+                };
+                let position = <[f32; 3]>::try_from(audio.positional_data.as_slice()).ok();
+                Ok(UdpPacket::Voice(VoicePacket {
+                    header,
+                    session_id: match origin {
+                        PacketOrigin::Client => None,
+                        PacketOrigin::Server => Some(audio.sender_session),
+                    },
+                    frame_number: audio.frame_number,
+                    payload: VoicePayload::Opus {
+                        data: audio.opus_data.into(),
+                        terminator: audio.is_terminator,
+                    },
+                    position,
+                    volume_adjustment: Some(audio.volume_adjustment).filter(|&volume| volume != 0.0),
+                }))
+            }
+            _ => Err(invalid_data("unknown voice packet type")),
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes a number in the variable length encoding of Mumble's `PacketDataStream`.
+fn write_varint(dst: &mut BytesMut, value: u64) {
+    if value < 0x80 {
+        dst.put_u8(value as u8);
+    } else if value < 0x4000 {
+        dst.put_u8((value >> 8) as u8 | 0x80);
+        dst.put_u8(value as u8);
+    } else if value < 0x20_0000 {
+        dst.put_u8((value >> 16) as u8 | 0xc0);
+        dst.put_u16(value as u16);
+    } else if value < 0x1000_0000 {
+        dst.put_u8((value >> 24) as u8 | 0xe0);
+        dst.put_u8((value >> 16) as u8);
+        dst.put_u16(value as u16);
+    } else if value < 0x1_0000_0000 {
+        dst.put_u8(0xf0);
+        dst.put_u32(value as u32);
+    } else {
+        dst.put_u8(0xf4);
+        dst.put_u64(value);
+    }
+}
+
+/// Reads a number in the variable length encoding of Mumble's `PacketDataStream`. Negative numbers
+/// are returned in two's complement.
+fn read_varint(buf: &mut &[u8]) -> Result<u64, io::Error> {
+    let truncated = || invalid_data("varint truncated");
+    let read = |buf: &mut &[u8], len: usize| -> Result<u64, io::Error> {
+        if buf.remaining() < len {
+            return Err(truncated());
+        }
+        Ok(buf.get_uint(len))
+    };
+
+    if !buf.has_remaining() {
+        return Err(truncated());
+    }
+    let first = u64::from(buf.get_u8());
+    Ok(if first & 0x80 == 0x00 {
+        first
+    } else if first & 0xc0 == 0x80 {
+        (first & 0x3f) << 8 | read(buf, 1)?
+    } else if first & 0xe0 == 0xc0 {
+        (first & 0x1f) << 16 | read(buf, 2)?
+    } else if first & 0xf0 == 0xe0 {
+        (first & 0x0f) << 24 | read(buf, 3)?
+    } else {
+        match first & 0xfc {
+            0xf0 => read(buf, 4)?,
+            0xf4 => read(buf, 8)?,
+            0xf8 => !read_varint(buf)?,
+            0xfc => !(first & 0x03),
+            _ => return Err(invalid_data("invalid varint")),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opus_packet(header: AudioHeader, session_id: Option<u32>) -> UdpPacket {
+        UdpPacket::Voice(VoicePacket {
+            header,
+            session_id,
+            frame_number: 300,
+            payload: VoicePayload::Opus { data: Bytes::from_static(&[1, 2, 3]), terminator: true },
+            position: Some([1.0, -2.5, 3.0]),
+            volume_adjustment: None,
+        })
+    }
+
+    fn round_trip(packet: &UdpPacket, format: VoicePacketFormat, origin: PacketOrigin) -> UdpPacket {
+        let mut buf = BytesMut::new();
+        packet.encode(format, &mut buf).unwrap();
+        UdpPacket::decode(&buf, format, origin).unwrap()
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 0x7f, 0x80, 0x3fff, 0x4000, 0x1f_ffff, 0x20_0000, 0xfff_ffff, 0x1000_0000, 0xffff_ffff, 0x1_0000_0000, u64::MAX] {
+            let mut buf = BytesMut::new();
+            write_varint(&mut buf, value);
+            assert_eq!(read_varint(&mut &buf[..]).unwrap(), value);
+        }
+        assert_eq!(read_varint(&mut &[0xfd][..]).unwrap(), !1);
+        assert_eq!(read_varint(&mut &[0xf8, 0x05][..]).unwrap(), !5);
+    }
+
+    #[test]
+    fn legacy_opus_from_server() {
+        let packet = opus_packet(AudioHeader::Context(AudioContext::Whisper), Some(7));
+        let mut buf = BytesMut::new();
+        packet.encode(VoicePacketFormat::Legacy, &mut buf).unwrap();
+
+        assert_eq!(&buf[..9], &[0x82, 7, 0x81, 0x2c, 0xa0, 0x03, 1, 2, 3]);
+        assert_eq!(UdpPacket::decode(&buf, VoicePacketFormat::Legacy, PacketOrigin::Server).unwrap(), packet);
+    }
+
+    #[test]
+    fn legacy_from_client_has_no_session() {
+        let packet = opus_packet(AudioHeader::Target(TARGET_SERVER_LOOPBACK), None);
+        assert_eq!(round_trip(&packet, VoicePacketFormat::Legacy, PacketOrigin::Client), packet);
+
+        let speex = UdpPacket::Voice(VoicePacket {
+            header: AudioHeader::Target(TARGET_NORMAL),
+            session_id: None,
+            frame_number: 1,
+            payload: VoicePayload::Speex(vec![Bytes::from_static(&[9; 20]), Bytes::new()]),
+            position: None,
+            volume_adjustment: None,
+        });
+        assert_eq!(round_trip(&speex, VoicePacketFormat::Legacy, PacketOrigin::Client), speex);
+    }
+
+    #[test]
+    fn legacy_position_is_little_endian() {
+        // Laid out the way the Mumble client writes an Opus packet with positional audio
+        let bytes = [
+            0x80, 0x05, 0x03, 1, 2, 3,
+            0x00, 0x00, 0x80, 0x3f,
+            0x00, 0x00, 0x20, 0xc0,
+            0x00, 0x00, 0x40, 0x40
+        ];
+        let packet = UdpPacket::Voice(VoicePacket {
+            header: AudioHeader::Target(TARGET_NORMAL),
+            session_id: None,
+            frame_number: 5,
+            payload: VoicePayload::Opus { data: Bytes::from_static(&[1, 2, 3]), terminator: false },
+            position: Some([1.0, -2.5, 3.0]),
+            volume_adjustment: None,
+        });
+
+        assert_eq!(UdpPacket::decode(&bytes, VoicePacketFormat::Legacy, PacketOrigin::Client).unwrap(), packet);
+        let mut buf = BytesMut::new();
+        packet.encode(VoicePacketFormat::Legacy, &mut buf).unwrap();
+        assert_eq!(&buf[..], &bytes);
+    }
+
+    #[test]
+    fn protobuf_round_trip() {
+        let packet = opus_packet(AudioHeader::Context(AudioContext::Listen), Some(7));
+        assert_eq!(round_trip(&packet, VoicePacketFormat::Protobuf, PacketOrigin::Server), packet);
+
+        let ping = UdpPacket::Ping(VoicePing { timestamp: 42, request_extended_information: true, ..Default::default() });
+        assert_eq!(round_trip(&ping, VoicePacketFormat::Protobuf, PacketOrigin::Client), ping);
+        assert_eq!(round_trip(&ping, VoicePacketFormat::Legacy, PacketOrigin::Client),
+                   UdpPacket::Ping(VoicePing { timestamp: 42, ..Default::default() }));
+    }
+
+    #[test]
+    fn negotiates_protobuf_format_from_1_5() {
+        let version_1_4 = (1 << 16) | (4 << 8);
+        assert_eq!(VoicePacketFormat::negotiate(PROTOBUF_FORMAT_MIN_VERSION, version_1_4), VoicePacketFormat::Legacy);
+        assert_eq!(VoicePacketFormat::negotiate(PROTOBUF_FORMAT_MIN_VERSION, PROTOBUF_FORMAT_MIN_VERSION + 1), VoicePacketFormat::Protobuf);
+    }
+}
@@ -0,0 +1,51 @@
+## Notifications
+
+notification-join = 🎧➕ { $user } { $count ->
+        [one] ist Mumble beigetreten
+       *[other] sind Mumble beigetreten
+    }
+notification-leave = 🎧➖ { $user } ist nach { $duration } gegangen
+notification-channel-switch = 🎧➡️ { $user } ist in { $channel } gewechselt
+notification-mute = 🎧 { $user } ist jetzt { $state ->
+        [deafened] taub gestellt
+        [muted] stummgeschaltet
+       *[unmuted] nicht mehr stummgeschaltet
+    }
+notification-channel-created = 📂➕ Kanal { $channel } wurde erstellt
+notification-channel-deleted = 📂➖ Kanal { $channel } wurde gelöscht
+
+## Status
+
+pinned-status = 🎧 <b>{ $server_name }</b> · { $count ->
+        [0] niemand online
+        [one] 1 Person online
+       *[other] { $count } Personen online
+    } · seit { $connected_for } verbunden
+    { $tree }
+pinned-status-refresh = 🔄 Aktualisieren
+who = { $count ->
+        [0] Gerade ist niemand auf Mumble
+       *[other] { $tree }
+    }
+talk-stats-header = 📊 Die Gesprächigsten der letzten Woche
+talk-stats-entry = { $rank }. { $user } · { $duration }
+
+## Commands
+
+help-header = Folgende Befehle werden unterstützt:
+command-help = Diese Hilfe anzeigen
+command-who = Anzeigen, wer auf Mumble online ist
+command-link = Telegram-Konto mit deinem Mumble-Namen verknüpfen: /link <Mumble-Name>
+link-usage = Verwendung: /link <Mumble-Name>
+link-missing-username = Du musst einen Telegram-Benutzernamen festlegen, bevor du ihn verknüpfen kannst
+link-configured = { $mumble_name } ist bereits in der Bot-Konfiguration zugeordnet
+link-taken = { $mumble_name } ist bereits mit jemand anderem verknüpft
+link-success = { $mention } wurde mit Mumble verknüpft
+
+## Formatting
+
+list-and = { $init } und { $last }
+duration-days = { $days } T. { $hours } Std.
+duration-hours = { $hours } Std. { $minutes } Min.
+duration-minutes = { $minutes } Min.
+duration-seconds = { $seconds } Sek.
@@ -0,0 +1,48 @@
+## Notifications
+
+notification-join = 🎧➕ { $user } joined mumble
+notification-leave = 🎧➖ { $user } left after { $duration }
+notification-channel-switch = 🎧➡️ { $user } moved to { $channel }
+notification-mute = 🎧 { $user } is now { $state ->
+        [deafened] deafened
+        [muted] muted
+       *[unmuted] unmuted
+    }
+notification-channel-created = 📂➕ Channel { $channel } was created
+notification-channel-deleted = 📂➖ Channel { $channel } was deleted
+
+## Status
+
+pinned-status = 🎧 <b>{ $server_name }</b> · { $count ->
+        [0] 0 users online
+        [one] 1 user online
+       *[other] { $count } users online
+    } · connected for { $connected_for }
+    { $tree }
+pinned-status-refresh = 🔄 Refresh
+who = { $count ->
+        [0] Nobody is on mumble right now
+       *[other] { $tree }
+    }
+talk-stats-header = 📊 Most talkative last week
+talk-stats-entry = { $rank }. { $user } · { $duration }
+
+## Commands
+
+help-header = The following commands are supported:
+command-help = Display this help text
+command-who = List who is online on mumble
+command-link = Link your Telegram account to your mumble name: /link <mumble name>
+link-usage = Usage: /link <mumble name>
+link-missing-username = You need to set a Telegram username before you can link it
+link-configured = { $mumble_name } is already mapped in the bot configuration
+link-taken = { $mumble_name } is already linked to someone else
+link-success = Linked { $mention } to mumble
+
+## Formatting
+
+list-and = { $init } and { $last }
+duration-days = { $days }d { $hours }h
+duration-hours = { $hours }h { $minutes }m
+duration-minutes = { $minutes }m
+duration-seconds = { $seconds }s
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use mumble_client_rs::client::stateful_mumble_client::channel::ChannelState;
+use mumble_client_rs::client::stateful_mumble_client::user::UserState;
+use crate::formatting;
+use crate::i18n::Localizer;
+use crate::username_map::UsernameMap;
+
+/// A channel together with the users in it and its sub-channels, containing only channels which have
+/// users somewhere below them.
+pub struct ChannelNode {
+    pub channel: ChannelState,
+    pub users: Vec<UserState>,
+    pub children: Vec<ChannelNode>
+}
+
+impl ChannelNode {
+    /// Builds the tree below the root channel. Users in channels we don't know about are placed in the root.
+    /// Returns `None` if nobody is online.
+    pub fn build(channels: Vec<ChannelState>, users: Vec<UserState>) -> Option<ChannelNode> {
+        let root = channels.iter().find(|c| c.parent_channel_id.is_none())?.clone();
+
+        let mut users_by_channel: HashMap<u32, Vec<UserState>> = HashMap::new();
+        for user in users {
+            let channel_id = user.current_channel_id
+                .filter(|id| channels.iter().any(|c| c.id == *id))
+                .unwrap_or(root.id);
+            users_by_channel.entry(channel_id).or_default().push(user);
+        }
+
+        let mut children_by_parent: HashMap<u32, Vec<ChannelState>> = HashMap::new();
+        for channel in channels {
+            if let Some(parent_id) = channel.parent_channel_id {
+                children_by_parent.entry(parent_id).or_default().push(channel);
+            }
+        }
+
+        Self::build_node(root, &mut users_by_channel, &mut children_by_parent)
+    }
+
+    fn build_node(
+        channel: ChannelState,
+        users_by_channel: &mut HashMap<u32, Vec<UserState>>,
+        children_by_parent: &mut HashMap<u32, Vec<ChannelState>>) -> Option<ChannelNode> {
+        let mut users = users_by_channel.remove(&channel.id).unwrap_or_default();
+        users.sort_by_key(|u| u.name.to_lowercase());
+
+        let mut child_channels = children_by_parent.remove(&channel.id).unwrap_or_default();
+        child_channels.sort_by_key(|c| c.name.to_lowercase());
+        let children: Vec<ChannelNode> = child_channels.into_iter()
+            .filter_map(|child| Self::build_node(child, users_by_channel, children_by_parent))
+            .collect();
+
+        if users.is_empty() && children.is_empty() {
+            return None;
+        }
+
+        Some(ChannelNode { channel, users, children })
+    }
+
+    /// Renders the tree as Telegram HTML, one line per channel and user, indented by depth.
+    pub fn to_telegram_html(&self, username_map: &UsernameMap, localizer: &Localizer) -> String {
+        let mut lines = vec![];
+        self.render(0, username_map, localizer, &mut lines);
+        lines.join("\n")
+    }
+
+    fn render(&self, depth: usize, username_map: &UsernameMap, localizer: &Localizer, lines: &mut Vec<String>) {
+        let indent = "    ".repeat(depth);
+        lines.push(format!("{}📂 <b>{}</b>", indent, formatting::escape_html(&self.channel.name)));
+        for user in &self.users {
+            lines.push(format!(
+                "{}    {}{}{} {} · {}",
+                indent,
+                user_status_indicator(user),
+                if user.recording { "🔴" } else { "" },
+                if user.is_talking() { "🔊" } else { "" },
+                formatting::escape_html(&username_map.display_name(&user.name)),
+                localizer.format_duration(user.online_duration())));
+        }
+        for child in &self.children {
+            child.render(depth + 1, username_map, localizer, lines);
+        }
+    }
+}
+
+fn user_status_indicator(user: &UserState) -> &'static str {
+    if user.deafened {
+        "🙉"
+    }
+    else if user.muted {
+        "🔇"
+    }
+    else {
+        "🎙"
+    }
+}
+
@@ -0,0 +1,577 @@
+use mumble_client_rs::client::stateful_mumble_client::server::ServerState;
+use teloxide::types::{MessageEntityKind, MessageEntityRef};
+
+/// Telegram rejects messages longer than this, counted after entity parsing.
+pub const TELEGRAM_MAX_MESSAGE_LENGTH: usize = 4096;
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Builds the Telegram HTML messages for a bridged Mumble message, attributed to `[origin] sender`. Messages that
+/// are too long for Telegram lose their formatting and are split up.
+pub fn mumble_to_telegram_messages(origin: &str, sender: &str, html: &str) -> Vec<String> {
+    let message = format!("<b>[{}] {}:</b> {}", escape_html(origin), escape_html(sender), mumble_html_to_telegram_html(html));
+    if message.len() <= TELEGRAM_MAX_MESSAGE_LENGTH {
+        return vec![message];
+    }
+
+    let plain_text = format!("[{}] {}: {}", origin, sender, mumble_html_to_plain_text(html));
+    split_escaped(&plain_text, TELEGRAM_MAX_MESSAGE_LENGTH, escape_html)
+}
+
+/// Builds the Mumble messages for a bridged Telegram message, split up if it exceeds the server's message length.
+pub fn telegram_to_mumble_messages(sender: &str, text: &str, entities: &[MessageEntityRef], server_state: &ServerState) -> Vec<String> {
+    let allow_html = server_state.allow_html.unwrap_or(true);
+    let max_length = server_state.max_message_length.unwrap_or(0) as usize;
+
+    let message = match allow_html {
+        true => format!("<b>[Telegram] {}:</b> {}", escape_html(sender), telegram_to_mumble_html(text, entities)),
+        // Mumble strips any markup when HTML is not allowed, so only escape the text
+        false => escape_html(&format!("[Telegram] {}: {}", sender, text))
+    };
+    if max_length == 0 || message.len() <= max_length {
+        return vec![message];
+    }
+
+    let plain_text = format!("[Telegram] {}: {}", sender, text);
+    match allow_html {
+        true => split_escaped(&plain_text, max_length, escape_mumble_text),
+        false => split_escaped(&plain_text, max_length, escape_html)
+    }
+}
+
+/// Converts a Mumble text message, which is (Qt flavoured) HTML, into the HTML subset supported by Telegram.
+/// Unsupported tags are dropped while their text content is kept.
+pub fn mumble_html_to_telegram_html(html: &str) -> String {
+    let mut output = String::new();
+    let mut open_elements: Vec<(String, Vec<&'static str>)> = vec![];
+    let mut code_depth = 0;
+
+    for token in tokenize_html(html) {
+        match token {
+            HtmlToken::Text(text) => output.push_str(&escape_html(&text)),
+            HtmlToken::StartTag { name, attributes, self_closing } => {
+                match name.as_str() {
+                    "br" => output.push('\n'),
+                    "li" => output.push_str("• "),
+                    _ => {}
+                }
+                if self_closing || is_void_element(&name) {
+                    continue;
+                }
+
+                let telegram_tags = if code_depth > 0 {
+                    // Telegram does not allow any formatting inside code blocks
+                    vec![]
+                } else {
+                    telegram_tags_for_element(&name, &attributes)
+                };
+                for tag in &telegram_tags {
+                    match *tag {
+                        "a" => output.push_str(&format!("<a href=\"{}\">", escape_html(attribute(&attributes, "href").unwrap_or_default()))),
+                        tag => output.push_str(&format!("<{}>", tag))
+                    }
+                    if matches!(*tag, "code" | "pre") {
+                        code_depth += 1;
+                    }
+                }
+                open_elements.push((name, telegram_tags));
+            },
+            HtmlToken::EndTag(name) => {
+                let Some(position) = open_elements.iter().rposition(|(open_name, _)| *open_name == name) else {
+                    continue;
+                };
+                for (_, telegram_tags) in open_elements.drain(position..).rev() {
+                    close_telegram_tags(&mut output, &telegram_tags, &mut code_depth);
+                }
+                if is_block_element(&name) {
+                    output.push('\n');
+                }
+            }
+        }
+    }
+
+    for (_, telegram_tags) in open_elements.into_iter().rev() {
+        close_telegram_tags(&mut output, &telegram_tags, &mut code_depth);
+    }
+
+    output.trim().to_string()
+}
+
+/// Strips all markup from a Mumble text message, leaving only the text a user would see.
+pub fn mumble_html_to_plain_text(html: &str) -> String {
+    let mut output = String::new();
+    for token in tokenize_html(html) {
+        match token {
+            HtmlToken::Text(text) => output.push_str(&text),
+            HtmlToken::StartTag { name, .. } if name == "br" => output.push('\n'),
+            HtmlToken::EndTag(name) if is_block_element(&name) => output.push('\n'),
+            _ => {}
+        }
+    }
+    output.trim().to_string()
+}
+
+/// Returns the `src` of every image embedded in a Mumble text message.
+pub fn mumble_html_image_sources(html: &str) -> Vec<String> {
+    tokenize_html(html).into_iter()
+        .filter_map(|token| match token {
+            HtmlToken::StartTag { name, attributes, .. } if name == "img" => attribute(&attributes, "src").map(str::to_string),
+            _ => None
+        })
+        .collect()
+}
+
+/// Converts a Telegram message and its formatting entities into Mumble HTML.
+pub fn telegram_to_mumble_html(text: &str, entities: &[MessageEntityRef]) -> String {
+    // Entities may nest, so open and close tags at their boundaries. Closing tags sort before opening ones at the
+    // same position, the outermost entity is opened first and closed last, and entities spanning the same text
+    // close in the reverse order they were opened in.
+    let mut boundaries = vec![];
+    for (index, entity) in entities.iter().enumerate() {
+        if let Some(tag) = mumble_tag_for_entity(entity) {
+            boundaries.push((entity.start(), 1, usize::MAX - entity.len(), index, tag.clone()));
+            boundaries.push((entity.end(), 0, entity.len(), usize::MAX - index, tag));
+        }
+    }
+    boundaries.sort();
+
+    let mut output = String::new();
+    let mut position = 0;
+    let mut open_tags: Vec<(usize, (&'static str, String))> = vec![];
+    for (boundary, is_opening, _, index, (tag, attributes)) in boundaries {
+        output.push_str(&escape_mumble_text(&text[position..boundary]));
+        position = boundary;
+
+        if is_opening == 1 {
+            output.push_str(&format!("<{}{}>", tag, attributes));
+            open_tags.push((index, (tag, attributes)));
+            continue;
+        }
+
+        // Partially overlapping entities close the ones opened inside them and reopen them afterwards
+        let index = usize::MAX - index;
+        let Some(open_position) = open_tags.iter().rposition(|(open_index, _)| *open_index == index) else {
+            continue;
+        };
+        let reopened = open_tags.split_off(open_position + 1);
+        for (_, (tag, _)) in reopened.iter().rev() {
+            output.push_str(&format!("</{}>", tag));
+        }
+        output.push_str(&format!("</{}>", tag));
+        open_tags.pop();
+        for (_, (tag, attributes)) in &reopened {
+            output.push_str(&format!("<{}{}>", tag, attributes));
+        }
+        open_tags.extend(reopened);
+    }
+    output.push_str(&escape_mumble_text(&text[position..]));
+
+    output
+}
+
+/// Splits text into chunks that stay within `max_length` once escaped, preferring to break at whitespace.
+/// A `max_length` of zero means there is no limit.
+pub fn split_escaped(text: &str, max_length: usize, escape: fn(&str) -> String) -> Vec<String> {
+    if max_length == 0 || escape(text).len() <= max_length {
+        return vec![escape(text)];
+    }
+
+    let mut chunks = vec![];
+    let mut current = String::new();
+    let mut current_length = 0;
+    let mut last_whitespace = None;
+    for c in text.chars() {
+        let escaped_length = escape(c.encode_utf8(&mut [0; 4])).len();
+        if current_length + escaped_length > max_length && !current.is_empty() {
+            // Break right here if the character that doesn't fit is whitespace itself
+            let split_at = match c.is_whitespace() {
+                true => current.len(),
+                false => last_whitespace.filter(|split_at| *split_at > 0).unwrap_or(current.len())
+            };
+            let remainder = current.split_off(split_at);
+            chunks.push(escape(current.trim_end()));
+            current = remainder.trim_start().to_string();
+            current_length = escape(&current).len();
+            last_whitespace = None;
+            if current.is_empty() && c.is_whitespace() {
+                continue;
+            }
+        }
+        if c.is_whitespace() {
+            last_whitespace = Some(current.len());
+        }
+        current.push(c);
+        current_length += escaped_length;
+    }
+    if !current.is_empty() {
+        chunks.push(escape(&current));
+    }
+
+    chunks
+}
+
+/// Splits Telegram HTML whose tags don't span lines, like the channel tree, into messages within `max_length` at
+/// line breaks. A line that is too long on its own loses its formatting and is split with [split_escaped].
+pub fn split_html_lines(html: &str, max_length: usize) -> Vec<String> {
+    let mut messages = vec![];
+    let mut current = String::new();
+    for line in html.lines() {
+        let lines = match line.len() > max_length {
+            true => split_escaped(&mumble_html_to_plain_text(line), max_length, escape_html),
+            false => vec![line.to_string()]
+        };
+        for line in lines {
+            if !current.is_empty() && current.len() + 1 + line.len() > max_length {
+                messages.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(&line);
+        }
+    }
+    if !current.trim().is_empty() {
+        messages.push(current);
+    }
+    messages
+}
+
+/// Escapes plain text for Mumble, which renders newlines only as explicit line breaks.
+pub fn escape_mumble_text(text: &str) -> String {
+    escape_html(text).replace('\n', "<br/>")
+}
+
+fn mumble_tag_for_entity(entity: &MessageEntityRef) -> Option<(&'static str, String)> {
+    match entity.kind() {
+        MessageEntityKind::Bold => Some(("b", String::new())),
+        MessageEntityKind::Italic => Some(("i", String::new())),
+        MessageEntityKind::Underline => Some(("u", String::new())),
+        MessageEntityKind::Strikethrough => Some(("s", String::new())),
+        MessageEntityKind::Code => Some(("code", String::new())),
+        MessageEntityKind::Pre { .. } => Some(("pre", String::new())),
+        MessageEntityKind::TextLink { url } => Some(("a", format!(" href=\"{}\"", escape_html(url.as_str())))),
+        MessageEntityKind::Url => Some(("a", format!(" href=\"{}\"", escape_html(&url_with_scheme(entity.text()))))),
+        _ => None
+    }
+}
+
+/// Telegram also detects links like `example.com`, which would be relative without a scheme.
+fn url_with_scheme(url: &str) -> String {
+    match url.contains("://") || url.starts_with("mailto:") {
+        true => url.to_string(),
+        false => format!("http://{}", url)
+    }
+}
+
+fn telegram_tags_for_element(name: &str, attributes: &[(String, String)]) -> Vec<&'static str> {
+    match name {
+        "b" | "strong" => vec!["b"],
+        "i" | "em" => vec!["i"],
+        "u" | "ins" => vec!["u"],
+        "s" | "strike" | "del" => vec!["s"],
+        "code" | "tt" | "kbd" | "samp" => vec!["code"],
+        "pre" => vec!["pre"],
+        "a" if attribute(attributes, "href").is_some_and(is_supported_link) => vec!["a"],
+        "span" | "p" | "font" => telegram_tags_for_style(attribute(attributes, "style").unwrap_or_default()),
+        _ => vec![]
+    }
+}
+
+/// Qt expresses most formatting through inline styles rather than dedicated tags.
+fn telegram_tags_for_style(style: &str) -> Vec<&'static str> {
+    let mut tags = vec![];
+    for declaration in style.split(';') {
+        let Some((property, value)) = declaration.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match property.trim() {
+            "font-weight" if value == "bold" || value.parse::<u32>().is_ok_and(|weight| weight >= 600) => tags.push("b"),
+            "font-style" if value == "italic" => tags.push("i"),
+            "text-decoration" if value.contains("underline") => tags.push("u"),
+            "text-decoration" if value.contains("line-through") => tags.push("s"),
+            _ => {}
+        }
+    }
+    tags
+}
+
+fn close_telegram_tags(output: &mut String, telegram_tags: &[&'static str], code_depth: &mut usize) {
+    for tag in telegram_tags.iter().rev() {
+        output.push_str(&format!("</{}>", tag));
+        if matches!(*tag, "code" | "pre") {
+            *code_depth -= 1;
+        }
+    }
+}
+
+fn is_supported_link(href: &str) -> bool {
+    ["http://", "https://", "mailto:", "tg://"].iter().any(|scheme| href.starts_with(scheme))
+}
+
+fn is_void_element(name: &str) -> bool {
+    matches!(name, "br" | "img" | "hr" | "meta" | "link" | "input")
+}
+
+fn is_block_element(name: &str) -> bool {
+    matches!(name, "p" | "div" | "li" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "blockquote" | "pre")
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+}
+
+enum HtmlToken {
+    Text(String),
+    StartTag { name: String, attributes: Vec<(String, String)>, self_closing: bool },
+    EndTag(String)
+}
+
+/// A forgiving tokenizer for the HTML produced by Mumble clients. Comments, doctypes and the contents of
+/// `head`, `style` and `script` elements are skipped, entities in text and attributes are decoded.
+fn tokenize_html(html: &str) -> Vec<HtmlToken> {
+    let mut tokens = vec![];
+    let mut remaining = html;
+    let mut skip_until: Option<String> = None;
+
+    while !remaining.is_empty() {
+        let Some(tag_start) = remaining.find('<') else {
+            if skip_until.is_none() {
+                tokens.push(HtmlToken::Text(decode_entities(remaining)));
+            }
+            break;
+        };
+        if tag_start > 0 && skip_until.is_none() {
+            tokens.push(HtmlToken::Text(decode_entities(&remaining[..tag_start])));
+        }
+        remaining = &remaining[tag_start..];
+
+        if let Some(comment) = remaining.strip_prefix("<!--") {
+            remaining = comment.find("-->").map(|end| &comment[end + 3..]).unwrap_or_default();
+            continue;
+        }
+
+        let Some(tag_end) = remaining.find('>') else {
+            // Not actually a tag, treat the rest as text
+            if skip_until.is_none() {
+                tokens.push(HtmlToken::Text(decode_entities(remaining)));
+            }
+            break;
+        };
+        let tag = &remaining[1..tag_end];
+        remaining = &remaining[tag_end + 1..];
+
+        if tag.starts_with('!') || tag.starts_with('?') {
+            continue;
+        }
+
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim().to_ascii_lowercase();
+            if skip_until.as_ref() == Some(&name) {
+                skip_until = None;
+            } else if skip_until.is_none() {
+                tokens.push(HtmlToken::EndTag(name));
+            }
+            continue;
+        }
+
+        if skip_until.is_some() {
+            continue;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, attributes) = match tag.find(char::is_whitespace) {
+            Some(name_end) => (&tag[..name_end], parse_attributes(&tag[name_end..])),
+            None => (tag, vec![])
+        };
+        let name = name.to_ascii_lowercase();
+
+        if matches!(name.as_str(), "head" | "style" | "script") && !self_closing {
+            skip_until = Some(name);
+            continue;
+        }
+        tokens.push(HtmlToken::StartTag { name, attributes, self_closing });
+    }
+
+    tokens
+}
+
+fn parse_attributes(mut input: &str) -> Vec<(String, String)> {
+    let mut attributes = vec![];
+    loop {
+        input = input.trim_start();
+        if input.is_empty() {
+            return attributes;
+        }
+
+        let name_end = input.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(input.len());
+        let name = input[..name_end].to_ascii_lowercase();
+        input = input[name_end..].trim_start();
+
+        let Some(value_start) = input.strip_prefix('=') else {
+            attributes.push((name, String::new()));
+            continue;
+        };
+        let value_start = value_start.trim_start();
+        let (value, rest) = match value_start.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let value_end = value_start[1..].find(quote).map(|end| end + 1).unwrap_or(value_start.len());
+                (&value_start[1..value_end], value_start.get(value_end + 1..).unwrap_or_default())
+            },
+            _ => {
+                let value_end = value_start.find(char::is_whitespace).unwrap_or(value_start.len());
+                (&value_start[..value_end], &value_start[value_end..])
+            }
+        };
+        attributes.push((name, decode_entities(value)));
+        input = rest;
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut remaining = text;
+    while let Some(entity_start) = remaining.find('&') {
+        decoded.push_str(&remaining[..entity_start]);
+        remaining = &remaining[entity_start..];
+
+        let entity = remaining.find(';')
+            .filter(|entity_end| *entity_end <= 10)
+            .and_then(|entity_end| decode_entity(&remaining[1..entity_end]).map(|c| (c, entity_end)));
+        match entity {
+            Some((c, entity_end)) => {
+                decoded.push(c);
+                remaining = &remaining[entity_end + 1..];
+            },
+            None => {
+                decoded.push('&');
+                remaining = &remaining[1..];
+            }
+        }
+    }
+    decoded.push_str(remaining);
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code_point = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => entity.strip_prefix('#')?.parse().ok()?
+            };
+            char::from_u32(code_point)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::MessageEntity;
+    use super::*;
+
+    fn to_mumble(text: &str, entities: &[MessageEntity]) -> String {
+        telegram_to_mumble_html(text, &MessageEntityRef::parse(text, entities))
+    }
+
+    #[test]
+    fn escapes_html_special_characters() {
+        assert_eq!(escape_html(r#"<b>"Tom" & 'Jerry'</b>"#), "&lt;b&gt;&quot;Tom&quot; &amp; 'Jerry'&lt;/b&gt;");
+        assert_eq!(to_mumble("a < b & c\nd", &[]), "a &lt; b &amp; c<br/>d");
+        assert_eq!(mumble_html_to_telegram_html("a &lt; b &amp;amp; <i>\"c\"</i>"), "a &lt; b &amp;amp; <i>&quot;c&quot;</i>");
+    }
+
+    #[test]
+    fn nests_entities() {
+        let entities = [MessageEntity::bold(0, 11), MessageEntity::italic(6, 5)];
+        assert_eq!(to_mumble("hello world", &entities), "<b>hello <i>world</i></b>");
+    }
+
+    #[test]
+    fn closes_entities_with_the_same_span_in_reverse_order() {
+        let entities = [MessageEntity::bold(0, 5), MessageEntity::italic(0, 5)];
+        assert_eq!(to_mumble("hello", &entities), "<b><i>hello</i></b>");
+    }
+
+    #[test]
+    fn reopens_partially_overlapping_entities() {
+        let entities = [MessageEntity::bold(0, 4), MessageEntity::italic(2, 4)];
+        assert_eq!(to_mumble("abcdef", &entities), "<b>ab<i>cd</i></b><i>ef</i>");
+    }
+
+    #[test]
+    fn escapes_link_attributes() {
+        let url = "https://example.com/?a=1&b=\"2\"".parse().unwrap();
+        let entities = [MessageEntity::text_link(url, 0, 4)];
+        assert_eq!(to_mumble("link", &entities), "<a href=\"https://example.com/?a=1&amp;b=%222%22\">link</a>");
+
+        let html = mumble_html_to_telegram_html("<a href=\"https://example.com/?a=1&amp;b=&quot;2&quot;\">link</a>");
+        assert_eq!(html, "<a href=\"https://example.com/?a=1&amp;b=&quot;2&quot;\">link</a>");
+    }
+
+    #[test]
+    fn adds_scheme_to_detected_urls() {
+        let entities = [MessageEntity::new(MessageEntityKind::Url, 4, 11)];
+        assert_eq!(to_mumble("see example.com", &entities), "see <a href=\"http://example.com\">example.com</a>");
+
+        let entities = [MessageEntity::new(MessageEntityKind::Url, 0, 19)];
+        assert_eq!(to_mumble("https://example.com", &entities), "<a href=\"https://example.com\">https://example.com</a>");
+    }
+
+    #[test]
+    fn splits_long_messages_within_the_limit() {
+        let html = "<b>bold</b> ".to_string() + &"a&b ".repeat(2000);
+        let messages = mumble_to_telegram_messages("Mumble", "Alice", &html);
+
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert!(message.len() <= TELEGRAM_MAX_MESSAGE_LENGTH);
+            // Formatting is dropped and no entity is cut in half
+            assert!(!message.contains('<'));
+            assert_eq!(message.matches('&').count(), message.matches("&amp;").count());
+        }
+        let text: Vec<String> = messages.iter().map(|message| decode_entities(message)).collect();
+        assert_eq!(text.join(" "), format!("[Mumble] Alice: bold {}", "a&b ".repeat(2000).trim_end()));
+    }
+
+    #[test]
+    fn splits_html_at_line_breaks() {
+        let line = format!("📂 <b>{}</b>", "a".repeat(20));
+        let html = [line.as_str(); 10].join("\n");
+        let messages = split_html_lines(&html, line.len() * 3 + 2);
+
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0], [line.as_str(); 3].join("\n"));
+        assert_eq!(messages.join("\n"), html);
+
+        let long_line = format!("<b>{}</b>", "a&".repeat(10));
+        assert_eq!(split_html_lines(&long_line, 12), vec!["a&amp;a&amp;", "a&amp;a&amp;", "a&amp;a&amp;", "a&amp;a&amp;", "a&amp;a&amp;"]);
+    }
+
+    #[test]
+    fn splits_at_whitespace() {
+        assert_eq!(split_escaped("aaa bbb ccc", 7, escape_html), vec!["aaa bbb", "ccc"]);
+        assert_eq!(split_escaped("a&b a&b", 7, escape_html), vec!["a&amp;b", "a&amp;b"]);
+        assert_eq!(split_escaped("a&b", 5, escape_html), vec!["a", "&amp;", "b"]);
+        assert_eq!(split_escaped("abcdef", 4, escape_html), vec!["abcd", "ef"]);
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use log::error;
+use unic_langid::LanguageIdentifier;
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+const LOCALES: [(&str, &str); 2] = [
+    ("en", include_str!("../locales/en.ftl")),
+    ("de", include_str!("../locales/de.ftl"))
+];
+
+/// Every message the bot looks up, checked when loading a locale so a missing translation fails at startup.
+const REQUIRED_MESSAGES: [&str; 25] = [
+    "notification-join",
+    "notification-leave",
+    "notification-channel-switch",
+    "notification-mute",
+    "notification-channel-created",
+    "notification-channel-deleted",
+    "pinned-status",
+    "pinned-status-refresh",
+    "who",
+    "talk-stats-header",
+    "talk-stats-entry",
+    "help-header",
+    "command-help",
+    "command-who",
+    "command-link",
+    "link-usage",
+    "link-missing-username",
+    "link-configured",
+    "link-taken",
+    "link-success",
+    "list-and",
+    "duration-days",
+    "duration-hours",
+    "duration-minutes",
+    "duration-seconds"
+];
+
+#[derive(Debug)]
+pub struct LocaleError {
+    locale: String,
+    reason: String
+}
+
+impl Display for LocaleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unable to load locale {}: {}", self.locale, self.reason)
+    }
+}
+
+impl std::error::Error for LocaleError {}
+
+/// Looks up the bot's messages in the Fluent catalogue of one of the bundled locales.
+#[derive(Clone)]
+pub struct Localizer {
+    bundle: Arc<FluentBundle<FluentResource>>
+}
+
+impl Localizer {
+    pub fn new(locale: &str) -> Result<Self, LocaleError> {
+        let locale_error = |reason: String| LocaleError { locale: locale.to_string(), reason };
+
+        let (_, source) = LOCALES.iter()
+            .find(|(name, _)| *name == locale)
+            .ok_or_else(|| locale_error(format!("supported locales are {}", LOCALES.map(|(name, _)| name).join(", "))))?;
+        let language: LanguageIdentifier = locale.parse()
+            .map_err(|err| locale_error(format!("{}", err)))?;
+        let resource = FluentResource::try_new(source.to_string())
+            .map_err(|(_, errors)| locale_error(format!("{:?}", errors)))?;
+
+        let mut bundle = FluentBundle::new_concurrent(vec![language]);
+        // Telegram renders the bidi isolation marks Fluent puts around arguments
+        bundle.set_use_isolating(false);
+        bundle.add_resource(resource)
+            .map_err(|errors| locale_error(format!("{:?}", errors)))?;
+
+        if let Some(missing) = REQUIRED_MESSAGES.iter().find(|id| !bundle.has_message(id)) {
+            return Err(locale_error(format!("message {} is missing", missing)));
+        }
+
+        Ok(Self { bundle: Arc::new(bundle) })
+    }
+
+    pub fn message(&self, id: &str, args: Option<&FluentArgs>) -> String {
+        let Some(pattern) = self.bundle.get_message(id).and_then(|message| message.value()) else {
+            error!("Message {} is missing from the locale", id);
+            return id.to_string();
+        };
+
+        let mut errors = vec![];
+        let message = self.bundle.format_pattern(pattern, args, &mut errors);
+        if !errors.is_empty() {
+            error!("Failed to format message {}: {:?}", id, errors);
+        }
+        message.into_owned()
+    }
+
+    /// Joins names into a readable list, e.g. `Alice, Bob and Carol`.
+    pub fn list(&self, names: &[&str]) -> String {
+        match names {
+            [] => String::new(),
+            [name] => name.to_string(),
+            [init @ .., last] => {
+                let mut args = FluentArgs::new();
+                args.set("init", init.join(", "));
+                args.set("last", *last);
+                self.message("list-and", Some(&args))
+            }
+        }
+    }
+
+    /// Formats a duration with its two most significant units, e.g. `2h 13m` or `45s`.
+    pub fn format_duration(&self, duration: Duration) -> String {
+        let seconds = duration.as_secs();
+        let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+
+        let mut args = FluentArgs::new();
+        args.set("days", days);
+        args.set("hours", hours);
+        args.set("minutes", minutes);
+        args.set("seconds", seconds);
+        let id = if days > 0 {
+            "duration-days"
+        }
+        else if hours > 0 {
+            "duration-hours"
+        }
+        else if minutes > 0 {
+            "duration-minutes"
+        }
+        else {
+            "duration-seconds"
+        };
+
+        self.message(id, Some(&args))
+    }
+}
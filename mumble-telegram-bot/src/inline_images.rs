@@ -0,0 +1,79 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use image::{DynamicImage, ImageError, ImageFormat};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use crate::formatting;
+
+/// Images are never posted larger than this, even if the server allows arbitrarily long image messages.
+const MAX_IMAGE_DIMENSION: u32 = 1280;
+const JPEG_QUALITIES: [u8; 3] = [85, 65, 45];
+
+/// Extracts the images Mumble clients embed in text messages as `data:` URIs.
+pub fn extract_mumble_images(html: &str) -> Vec<Vec<u8>> {
+    formatting::mumble_html_image_sources(html).into_iter()
+        .filter_map(|source| decode_data_uri(&source))
+        .collect()
+}
+
+/// Re-encodes an image as a JPEG `<img>` tag, downscaling it until the whole message, including `prefix`, fits
+/// into the server's image message length. A `max_length` of zero means the server does not limit image messages.
+pub fn encode_image_for_mumble(image_bytes: &[u8], prefix: &str, max_length: usize) -> Result<Option<String>, ImageError> {
+    let mut image = image::load_from_memory(image_bytes)?;
+    if image.width() > MAX_IMAGE_DIMENSION || image.height() > MAX_IMAGE_DIMENSION {
+        image = image.resize(MAX_IMAGE_DIMENSION, MAX_IMAGE_DIMENSION, FilterType::Triangle);
+    }
+
+    while image.width() >= 16 && image.height() >= 16 {
+        for quality in JPEG_QUALITIES {
+            let message = format!("{}<img src=\"data:image/jpeg;base64,{}\"/>", prefix, STANDARD.encode(encode_jpeg(&image, quality)?));
+            if max_length == 0 || message.len() <= max_length {
+                return Ok(Some(message));
+            }
+        }
+        image = image.resize(image.width() * 3 / 4, image.height() * 3 / 4, FilterType::Triangle);
+    }
+
+    Ok(None)
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, ImageError> {
+    let mut encoded = vec![];
+    JpegEncoder::new_with_quality(&mut encoded, quality).encode_image(&image.to_rgb8())?;
+    Ok(encoded)
+}
+
+fn decode_data_uri(source: &str) -> Option<Vec<u8>> {
+    let (header, data) = source.strip_prefix("data:")?.split_once(',')?;
+    if !header.starts_with("image/") || !header.ends_with(";base64") {
+        return None;
+    }
+
+    // Mumble percent-encodes the base64 payload
+    let image_bytes = STANDARD.decode(percent_decode(data).trim()).ok()?;
+    image::guess_format(&image_bytes).ok()
+        .filter(|format| matches!(format, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP))?;
+
+    Some(image_bytes)
+}
+
+fn percent_decode(input: &str) -> String {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let hex = [bytes.next(), bytes.next()];
+        match hex.map(|digit| digit.and_then(|d| (d as char).to_digit(16))) {
+            [Some(high), Some(low)] => decoded.push((high * 16 + low) as u8),
+            _ => {
+                decoded.push(byte);
+                decoded.extend(hex.into_iter().flatten());
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
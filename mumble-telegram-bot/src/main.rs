@@ -1,18 +1,30 @@
 #![feature(fs_try_exists)]
 
+use std::collections::BTreeMap;
 use settings::SettingsProvider;
 use log::{error, info};
+use mumble_client_rs::MumbleClientError;
 use tokio::signal;
 use crate::mumble_actor::MumbleActorHandle;
 use crate::state_file_actor::StateFileActorHandle;
 use crate::telegram_bot_actor::TelegramBotActorHandle;
 use crate::telegram_sender_actor::TelegramSenderActorHandle;
+use crate::templates::MessageTemplates;
+use crate::i18n::{Localizer, DEFAULT_LOCALE};
 
 mod settings;
 mod mumble_actor;
 mod telegram_sender_actor;
 mod telegram_bot_actor;
 mod state_file_actor;
+mod formatting;
+mod inline_images;
+mod username_map;
+mod channel_tree;
+mod notifications;
+mod talk_stats;
+mod templates;
+mod i18n;
 
 #[tokio::main]
 async fn main() {
@@ -22,18 +34,111 @@ async fn main() {
 
     info!("{:?}", config);
 
+    let mumble_servers = config.mumble_servers();
+    if mumble_servers.is_empty() {
+        error!("No mumble servers configured, set mumble_servers");
+        return;
+    }
+    let chats = config.chats();
+    if chats.is_empty() {
+        error!("No Telegram chats configured, set telegram.chats");
+        return;
+    }
+
     let state_file_actor_handle = StateFileActorHandle::new(&config.state_file_path);
-    let telegram_sender_actor_handle = TelegramSenderActorHandle::new(&config.telegram, state_file_actor_handle);
-    let (mumble_actor_handle, mumble_server_disconnected_handle) = MumbleActorHandle::new(config.mumble.clone(), telegram_sender_actor_handle.0.clone()).await;
-    let _telegram_bot_actor_handle = TelegramBotActorHandle::new(config.telegram.clone(), mumble_actor_handle.clone());
+    let legacy_chat_id = chats[0].chat_id;
+    state_file_actor_handle.update_state(move |state| state.migrate_legacy_pinned_message(legacy_chat_id)).await;
+
+    let mut telegram_chats = vec![];
+    for chat in &chats {
+        let locale = chat.locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+        let localizer = match Localizer::new(locale) {
+            Ok(localizer) => localizer,
+            Err(err) => {
+                error!("Unable to load message catalogue for chat {}: {}", chat.chat_id, err);
+                return;
+            }
+        };
+        let templates = match MessageTemplates::new(&chat.templates, localizer) {
+            Ok(templates) => templates,
+            Err(err) => {
+                error!("Unable to load message templates for chat {}: {}", chat.chat_id, err);
+                return;
+            }
+        };
+        let mut server_names = BTreeMap::new();
+        for server in chat.servers.iter().flatten() {
+            let Some(settings) = mumble_servers.get(server) else {
+                error!("Chat {} relays unknown mumble server {}", chat.chat_id, server);
+                return;
+            };
+            server_names.insert(server.clone(), settings.display_name());
+        }
+        let (telegram_sender_actor_handle, _) = TelegramSenderActorHandle::new(
+            &config.telegram,
+            chat,
+            server_names,
+            templates,
+            config.username_map.clone(),
+            state_file_actor_handle.clone());
+        telegram_chats.push(telegram_sender_actor_handle);
+    }
 
-    let mut core_task_handles = vec![];
-    core_task_handles.push(mumble_server_disconnected_handle);
-    core_task_handles.push(tokio::spawn(listen_for_sigterm()));
+    // Servers are connected to at the same time, so one that is down doesn't hold up the others
+    let connections = futures::future::join_all(mumble_servers.into_iter().map(|(server, settings)| {
+        let trust_on_first_use = settings.trust_on_first_use;
+        let mumble_actor = MumbleActorHandle::new(
+            server.clone(),
+            settings,
+            config.bridge.clone(),
+            config.username_map.clone(),
+            telegram_chats.clone(),
+            state_file_actor_handle.clone());
+        async move { (server, trust_on_first_use, mumble_actor.await) }
+    })).await;
+
+    let mut mumble_actor_handles = vec![];
+    let mut mumble_server_disconnected_handles = vec![];
+    for (server, trust_on_first_use, mumble_actor) in connections {
+        // One misconfigured server doesn't keep the others from being relayed
+        let (mumble_actor_handle, mumble_server_disconnected_handle) = match mumble_actor {
+            Ok(handles) => handles,
+            Err(MumbleClientError::Rejected { reject_type, reason }) => {
+                error!("Mumble server {} rejected the bot ({}), check the configured username and password: {}", server, reject_type.as_str_name(), reason);
+                continue;
+            },
+            Err(err) if trust_on_first_use && err.fingerprint_mismatch().is_some() => {
+                error!("Unable to connect to mumble server {}: {}. If the new certificate is expected, set it as acknowledged_certificate_fingerprint", server, err);
+                continue;
+            },
+            Err(err) => {
+                error!("Unable to connect to mumble server {}: {}", server, err);
+                continue;
+            }
+        };
+        mumble_actor_handles.push(mumble_actor_handle);
+        mumble_server_disconnected_handles.push((server, mumble_server_disconnected_handle));
+    }
+    if mumble_actor_handles.is_empty() {
+        error!("Unable to connect to any mumble server");
+        return;
+    }
+    tokio::spawn(talk_stats::run_weekly_talk_stats(telegram_chats.clone(), config.username_map.clone(), state_file_actor_handle.clone()));
+    let _telegram_bot_actor_handle = TelegramBotActorHandle::new(config.telegram.clone(), config.bridge.clone(), config.username_map.clone(), mumble_actor_handles, telegram_chats, state_file_actor_handle.clone());
 
     info!("Mumble Telegram Bot started up");
-    
-    let _ = futures::future::select_all(core_task_handles).await;
+
+    // The other servers are still relayed when the bot gives up on reconnecting to one of them
+    let all_servers_disconnected = futures::future::join_all(mumble_server_disconnected_handles.into_iter()
+        .map(|(server, mumble_server_disconnected_handle)| async move {
+            let _ = mumble_server_disconnected_handle.await;
+            error!("Gave up reconnecting to mumble server {}, it is no longer relayed", server);
+        }));
+    tokio::select! {
+        _ = listen_for_sigterm() => {},
+        _ = all_servers_disconnected => error!("Lost the connection to every mumble server, shutting down")
+    }
+    state_file_actor_handle.write_unsaved_changes().await;
 }
 
 async fn listen_for_sigterm() {
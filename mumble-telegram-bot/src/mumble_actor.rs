@@ -1,10 +1,31 @@
+use std::time::Duration;
+use log::warn;
 use tokio::sync::{oneshot, mpsc, broadcast};
 use tokio::task::JoinHandle;
 use mumble_client_rs::client::stateful_mumble_client::{MumbleEvent, StatefulMumbleClient};
-use mumble_client_rs::client::stateful_mumble_client::MumbleEvent::{UserJoinedServer, UserLeftServer, UserUpdated};
+use mumble_client_rs::client::stateful_mumble_client::MumbleEvent::{ChannelCreated, ChannelDeleted, ChannelUpdated, Disconnected, Reconnected, TextMessagePosted, UserJoinedServer, UserLeftServer, UserMuteChanged, UserStartedTalking, UserStoppedTalking, UserSwitchedChannel, UserUpdated};
+use mumble_client_rs::client::stateful_mumble_client::channel::ChannelState;
+use mumble_client_rs::client::stateful_mumble_client::server::ServerState;
+use mumble_client_rs::client::stateful_mumble_client::text_message::TextMessage;
 use mumble_client_rs::client::stateful_mumble_client::user::UserState;
-use crate::settings::MumbleSettings;
+use mumble_client_rs::{MumbleClientConfig, MumbleClientError, ServerCertificateVerification};
+use crate::{formatting, inline_images, talk_stats};
+use crate::notifications::Notification;
+use crate::settings::{BridgeSettings, MumbleSettings, UsernameMapping};
+use crate::state_file_actor::StateFileActorHandle;
 use crate::telegram_sender_actor::TelegramSenderActorHandle;
+use crate::username_map::UsernameMap;
+
+const ROOT_CHANNEL_ID: u32 = 0;
+
+/// Snapshot of the server shown in the pinned status message.
+#[derive(Clone)]
+pub struct MumbleStatus {
+    pub server_name: String,
+    pub server: ServerState,
+    pub channels: Vec<ChannelState>,
+    pub users: Vec<UserState>
+}
 
 struct MumbleSenderActor {
     receiver: mpsc::Receiver<MumbleSenderActorMessage>,
@@ -15,6 +36,20 @@ struct MumbleSenderActor {
 pub enum MumbleSenderActorMessage {
     GetActiveUsers {
         respond_to: oneshot::Sender<Vec<UserState>>,
+    },
+    GetChannels {
+        respond_to: oneshot::Sender<Vec<ChannelState>>
+    },
+    GetStatus {
+        respond_to: oneshot::Sender<MumbleStatus>
+    },
+    GetServerState {
+        respond_to: oneshot::Sender<ServerState>
+    },
+    SendTextMessage {
+        respond_to: oneshot::Sender<Result<(), MumbleClientError>>,
+        channel_name: Option<String>,
+        message: String
     }
 }
 
@@ -27,15 +62,48 @@ impl MumbleSenderActor {
         }
     }
 
+    fn active_users(&self) -> Vec<UserState> {
+        let mut users = self.mumble_client.get_current_online_users();
+        if self.mumble_settings.filter_out_inferred_bot_users {
+            users = users.into_iter().filter(|u| !u.infer_is_bot_user()).collect();
+        }
+        users
+    }
+
     async fn handle_message(&mut self, msg: MumbleSenderActorMessage) {
         match msg {
             MumbleSenderActorMessage::GetActiveUsers {respond_to} => {
-                let mut users = self.mumble_client.get_current_online_users();
-                if self.mumble_settings.filter_out_inferred_bot_users {
-                    users = users.into_iter().filter(|u| !u.infer_is_bot_user()).collect();
-                }
+                let _ = respond_to.send(self.active_users());
+            },
+            MumbleSenderActorMessage::GetStatus {respond_to} => {
+                let _ = respond_to.send(MumbleStatus {
+                    server_name: self.mumble_settings.display_name(),
+                    server: self.mumble_client.get_server_state(),
+                    channels: self.mumble_client.get_channels(),
+                    users: self.active_users()
+                });
+            },
+            MumbleSenderActorMessage::GetChannels {respond_to} => {
+                let _ = respond_to.send(self.mumble_client.get_channels());
+            },
+            MumbleSenderActorMessage::GetServerState {respond_to} => {
+                let _ = respond_to.send(self.mumble_client.get_server_state());
+            },
+            MumbleSenderActorMessage::SendTextMessage {respond_to, channel_name, message} => {
+                let channel_id = match channel_name {
+                    Some(name) => match self.mumble_client.get_channels().into_iter().find(|c| c.name == name) {
+                        Some(channel) => channel.id,
+                        None => {
+                            // Posting to the root channel instead could put the message in front of the wrong people
+                            warn!("Mumble channel {} doesn't exist, dropping message from Telegram", name);
+                            let _ = respond_to.send(Ok(()));
+                            return;
+                        }
+                    },
+                    None => ROOT_CHANNEL_ID
+                };
 
-                let _ = respond_to.send(users);
+                let _ = respond_to.send(self.mumble_client.send_text_message(channel_id, message).await);
             }
         }
     }
@@ -49,43 +117,182 @@ async fn run_mumble_sender_actor(mut actor: MumbleSenderActor) {
 
 struct MumbleEventReceiverActor {
     mumble_settings: MumbleSettings,
+    bridge_settings: BridgeSettings,
+    username_mappings: Vec<UsernameMapping>,
     mumble_event_receiver: broadcast::Receiver<MumbleEvent>,
     mumble_actor_handle: MumbleActorHandle,
-    telegram_sender_actor_handle: TelegramSenderActorHandle,
+    telegram_chats: Vec<TelegramSenderActorHandle>,
+    state_file_actor_handle: StateFileActorHandle
 }
 
 impl MumbleEventReceiverActor {
     fn new(
         mumble_settings: MumbleSettings,
+        bridge_settings: BridgeSettings,
+        username_mappings: Vec<UsernameMapping>,
         mumble_event_receiver: broadcast::Receiver<MumbleEvent>,
         mumble_actor_handle: MumbleActorHandle,
-        telegram_sender_actor_handle: TelegramSenderActorHandle) -> Self {
+        telegram_chats: Vec<TelegramSenderActorHandle>,
+        state_file_actor_handle: StateFileActorHandle) -> Self {
         MumbleEventReceiverActor {
             mumble_settings,
+            bridge_settings,
+            username_mappings,
             mumble_event_receiver,
             mumble_actor_handle,
-            telegram_sender_actor_handle
+            telegram_chats,
+            state_file_actor_handle
         }
     }
 
     async fn handle_message(&mut self, event: MumbleEvent) {
-        if matches!(event, UserJoinedServer(_) | UserLeftServer(_) | UserUpdated(_)) {
-            let users = self.mumble_actor_handle.get_active_users().await;
-            self.telegram_sender_actor_handle.update_pinned_mumble_status_message(users).await;
+        if matches!(event, UserJoinedServer(_) | UserLeftServer(_) | UserUpdated(_) | UserStartedTalking(_) | UserStoppedTalking(..) | Disconnected | Reconnected) {
+            let status = self.mumble_actor_handle.get_status().await;
+            for chat in &self.telegram_chats {
+                chat.update_pinned_mumble_status_message(self.mumble_actor_handle.server(), status.clone()).await;
+            }
         }
 
         match event {
             UserJoinedServer(user) => self.handle_user_joined_server_event(user).await,
+            UserLeftServer(user) => self.handle_user_left_server_event(user).await,
+            UserSwitchedChannel(user) => self.handle_user_switched_channel_event(user).await,
+            UserMuteChanged(user) => self.handle_user_mute_changed_event(user).await,
+            ChannelCreated(channel) => {
+                self.sync_forum_topic(&channel).await;
+                self.queue_notification(Notification::ChannelCreated {channel: channel.name}, None).await
+            },
+            ChannelUpdated(channel) => self.sync_forum_topic(&channel).await,
+            ChannelDeleted(channel) => {
+                self.close_forum_topic(&channel).await;
+                self.queue_notification(Notification::ChannelDeleted {channel: channel.name}, None).await
+            },
+            TextMessagePosted(message) => self.handle_text_message_posted_event(message).await,
+            UserStoppedTalking(user, duration) => self.handle_user_stopped_talking_event(user, duration).await,
             _ => {}
         }
     }
 
     async fn handle_user_joined_server_event(&mut self, user: UserState) {
-        if self.mumble_settings.filter_out_inferred_bot_users && user.infer_is_bot_user() {
+        if self.is_ignored_user(&user) {
+            return;
+        }
+
+        let display_name = self.display_name(&user).await;
+        let channel = self.user_channel(&user).await;
+        self.queue_notification(Notification::UserJoined {display_name}, channel).await
+    }
+
+    async fn handle_user_left_server_event(&mut self, user: UserState) {
+        if self.is_ignored_user(&user) {
+            return;
+        }
+
+        let display_name = self.display_name(&user).await;
+        let online_duration = user.online_duration();
+        let channel = self.user_channel(&user).await;
+        self.queue_notification(Notification::UserLeft {display_name, online_duration}, channel).await
+    }
+
+    async fn handle_user_switched_channel_event(&mut self, user: UserState) {
+        if self.is_ignored_user(&user) {
+            return;
+        }
+
+        let channel = self.user_channel(&user).await.map(|channel| channel.name).unwrap_or_default();
+        let display_name = self.display_name(&user).await;
+        self.queue_notification(Notification::UserSwitchedChannel {display_name, channel}, None).await
+    }
+
+    async fn handle_user_mute_changed_event(&mut self, user: UserState) {
+        if self.is_ignored_user(&user) {
             return;
         }
 
-        self.telegram_sender_actor_handle.send_telegram_message(format!("🎧➕ {} joined mumble", user.name)).await
+        let display_name = self.display_name(&user).await;
+        self.queue_notification(Notification::UserMuteChanged {
+            display_name,
+            muted: user.muted,
+            deafened: user.deafened
+        }, None).await
+    }
+
+    async fn handle_user_stopped_talking_event(&mut self, user: UserState, duration: Duration) {
+        if self.is_ignored_user(&user) {
+            return;
+        }
+
+        talk_stats::record_talk_time(&self.state_file_actor_handle, self.mumble_actor_handle.server(), &user.name, duration).await
+    }
+
+    fn is_ignored_user(&self, user: &UserState) -> bool {
+        self.mumble_settings.filter_out_inferred_bot_users && user.infer_is_bot_user()
+    }
+
+    async fn display_name(&self, user: &UserState) -> String {
+        let username_map = UsernameMap::load(&self.username_mappings, &self.state_file_actor_handle).await;
+        username_map.display_name(&user.name)
+    }
+
+    async fn channel(&self, channel_id: u32) -> Option<ChannelState> {
+        self.mumble_actor_handle.get_channels().await.into_iter().find(|channel| channel.id == channel_id)
+    }
+
+    async fn user_channel(&self, user: &UserState) -> Option<ChannelState> {
+        self.channel(user.current_channel_id.unwrap_or(ROOT_CHANNEL_ID)).await
+    }
+
+    /// Every chat gets every notification, each chat's sender drops the kinds it has disabled.
+    async fn queue_notification(&self, notification: Notification, channel: Option<ChannelState>) {
+        for chat in &self.telegram_chats {
+            chat.queue_notification(self.mumble_actor_handle.server(), notification.clone(), channel.clone()).await
+        }
+    }
+
+    async fn sync_forum_topic(&self, channel: &ChannelState) {
+        for chat in self.telegram_chats.iter().filter(|chat| chat.forum_topics()) {
+            chat.sync_forum_topic(self.mumble_actor_handle.server(), channel.clone()).await
+        }
+    }
+
+    async fn close_forum_topic(&self, channel: &ChannelState) {
+        for chat in self.telegram_chats.iter().filter(|chat| chat.forum_topics()) {
+            chat.close_forum_topic(self.mumble_actor_handle.server(), channel.id).await
+        }
+    }
+
+    async fn handle_text_message_posted_event(&mut self, message: TextMessage) {
+        if !self.bridge_settings.enabled || message.is_private() {
+            return;
+        }
+
+        // Never relay our own messages back, they originated from Telegram in the first place
+        let sender = match message.sender {
+            Some(sender) if sender.name != self.mumble_settings.username => sender,
+            _ => return
+        };
+
+        let channel_id = message.channel_ids.first().or(message.tree_ids.first()).copied();
+        let channel = match channel_id {
+            Some(channel_id) => self.channel(channel_id).await,
+            None => None
+        };
+        let server = self.mumble_actor_handle.server();
+        let has_text = !formatting::mumble_html_to_plain_text(&message.message).is_empty();
+        let images = inline_images::extract_mumble_images(&message.message);
+        for chat in self.telegram_chats.iter().filter(|chat| chat.bridge().mumble_to_telegram()) {
+            let origin = chat.server_prefix(server).unwrap_or("Mumble");
+            if has_text {
+                for telegram_message in formatting::mumble_to_telegram_messages(origin, &sender.name, &message.message) {
+                    chat.send_telegram_html_message(server, telegram_message, channel.clone()).await
+                }
+            }
+
+            for image in &images {
+                let caption = format!("<b>[{}] {}</b>", formatting::escape_html(origin), formatting::escape_html(&sender.name));
+                chat.send_telegram_photo(server, image.clone(), Some(caption), channel.clone()).await
+            }
+        }
     }
 }
 
@@ -97,27 +304,57 @@ async fn run_mumble_event_receiver_actor(mut actor: MumbleEventReceiverActor) {
 
 #[derive(Clone)]
 pub struct MumbleActorHandle {
-    sender: mpsc::Sender<MumbleSenderActorMessage>
+    sender: mpsc::Sender<MumbleSenderActorMessage>,
+    server: String
 }
 
 impl MumbleActorHandle {
-    pub async fn new(settings: MumbleSettings, telegram_sender_actor_handle: TelegramSenderActorHandle) -> (Self, JoinHandle<()>) {
-        let (mumble_client, mumble_server_disconnected_handle) = StatefulMumbleClient::connect(&settings.clone().into()).await.unwrap();
+    /// Connects to the server configured under `server` in `mumble_servers`, relaying it to the chats set up for it.
+    pub async fn new(
+        server: String,
+        settings: MumbleSettings,
+        bridge_settings: BridgeSettings,
+        username_mappings: Vec<UsernameMapping>,
+        telegram_chats: Vec<TelegramSenderActorHandle>,
+        state_file_actor_handle: StateFileActorHandle) -> Result<(Self, JoinHandle<()>), MumbleClientError> {
+        let mut config = MumbleClientConfig::try_from(settings.clone())?;
+        if let ServerCertificateVerification::TrustOnFirstUse { known: known @ None } = &mut config.server_certificate_verification {
+            let state = state_file_actor_handle.get_state().await;
+            *known = state.server_certificate_fingerprints.get(&server)
+                .map(|fingerprint| fingerprint.parse())
+                .transpose()?;
+        }
+        let (mumble_client, mumble_server_disconnected_handle) = StatefulMumbleClient::connect(&config).await?;
+
+        if let (ServerCertificateVerification::TrustOnFirstUse { .. }, Some(fingerprint)) = (&config.server_certificate_verification, mumble_client.get_server_state().certificate_fingerprint) {
+            let server = server.clone();
+            state_file_actor_handle.update_state(move |state| {
+                state.server_certificate_fingerprints.insert(server, fingerprint.to_string());
+            }).await;
+        }
 
         let (sender, receiver) = mpsc::channel(16);
 
-        let mumble_actor_handle = Self {sender};
+        let mumble_actor_handle = Self {sender, server};
 
         let mumble_event_receiver_actor = MumbleEventReceiverActor::new(
             settings.clone(),
+            bridge_settings,
+            username_mappings,
             mumble_client.subscribe_to_mumble_events(),
             mumble_actor_handle.clone(),
-            telegram_sender_actor_handle);
+            telegram_chats.into_iter().filter(|chat| chat.relays_server(&mumble_actor_handle.server)).collect(),
+            state_file_actor_handle);
         let sender_actor = MumbleSenderActor::new(receiver, mumble_client, settings.clone());
         let _sender_task = tokio::spawn(run_mumble_sender_actor(sender_actor));
         let _receiver_task = tokio::spawn(run_mumble_event_receiver_actor(mumble_event_receiver_actor));
 
-        (mumble_actor_handle, mumble_server_disconnected_handle)
+        Ok((mumble_actor_handle, mumble_server_disconnected_handle))
+    }
+
+    /// Name of the server in `mumble_servers`.
+    pub fn server(&self) -> &str {
+        &self.server
     }
 
     pub async fn get_active_users(&self) -> Vec<UserState> {
@@ -129,4 +366,47 @@ impl MumbleActorHandle {
         let _ = self.sender.send(msg).await;
         recv.await.expect("Mumble actor has been killed")
     }
+
+    pub async fn get_channels(&self) -> Vec<ChannelState> {
+        let (send, recv) = oneshot::channel();
+        let msg = MumbleSenderActorMessage::GetChannels {
+            respond_to: send
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Mumble actor has been killed")
+    }
+
+    pub async fn get_status(&self) -> MumbleStatus {
+        let (send, recv) = oneshot::channel();
+        let msg = MumbleSenderActorMessage::GetStatus {
+            respond_to: send
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Mumble actor has been killed")
+    }
+
+    pub async fn get_server_state(&self) -> ServerState {
+        let (send, recv) = oneshot::channel();
+        let msg = MumbleSenderActorMessage::GetServerState {
+            respond_to: send
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Mumble actor has been killed")
+    }
+
+    /// Posts a message to the named channel, or the root channel if no name is given or it doesn't exist.
+    pub async fn send_text_message(&self, channel_name: Option<String>, message: String) -> Result<(), MumbleClientError> {
+        let (send, recv) = oneshot::channel();
+        let msg = MumbleSenderActorMessage::SendTextMessage {
+            respond_to: send,
+            channel_name,
+            message
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Mumble actor has been killed")
+    }
 }
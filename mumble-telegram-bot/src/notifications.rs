@@ -0,0 +1,241 @@
+use std::time::Duration;
+use serde_json::json;
+use tokio::time::Instant;
+use crate::formatting::TELEGRAM_MAX_MESSAGE_LENGTH;
+use crate::settings::NotificationSettings;
+use crate::templates::{MessageTemplates, Template};
+
+/// A mumble event to announce, rendered by each chat with its own templates if it's enabled for the chat.
+#[derive(Clone)]
+pub enum Notification {
+    UserJoined {
+        display_name: String
+    },
+    UserLeft {
+        display_name: String,
+        online_duration: Duration
+    },
+    UserSwitchedChannel {
+        display_name: String,
+        channel: String
+    },
+    UserMuteChanged {
+        display_name: String,
+        muted: bool,
+        deafened: bool
+    },
+    ChannelCreated {
+        channel: String
+    },
+    ChannelDeleted {
+        channel: String
+    }
+}
+
+/// Collects notifications within the coalescing window so a burst of events is sent as a single message.
+/// Joins and leaves are held for the window counted from when each of them happened, so that a join and a leave
+/// of the same user within the window cancel each other out even if the batch is sent in between.
+#[derive(Default)]
+pub struct NotificationBatch {
+    notifications: Vec<PendingNotification>
+}
+
+struct PendingNotification {
+    notification: Notification,
+    queued_at: Instant
+}
+
+impl PendingNotification {
+    fn is_held(&self, now: Instant, coalesce_window: Duration) -> bool {
+        matches!(self.notification, Notification::UserJoined {..} | Notification::UserLeft {..})
+            && self.queued_at + coalesce_window > now
+    }
+}
+
+impl NotificationBatch {
+    pub fn push(&mut self, notification: Notification, queued_at: Instant) {
+        if !self.cancel(&notification) {
+            self.notifications.push(PendingNotification { notification, queued_at });
+        }
+    }
+
+    /// Drops a pending join or leave of the same user that `notification` cancels out, returning whether there
+    /// was one.
+    pub fn cancel(&mut self, notification: &Notification) -> bool {
+        let cancelled_by = match notification {
+            Notification::UserJoined {display_name} => self.notifications.iter().position(|pending| matches!(
+                &pending.notification, Notification::UserLeft {display_name: left, ..} if left == display_name)),
+            Notification::UserLeft {display_name, ..} => self.notifications.iter().position(|pending| matches!(
+                &pending.notification, Notification::UserJoined {display_name: joined} if joined == display_name)),
+            _ => None
+        };
+
+        cancelled_by.map(|index| self.notifications.remove(index)).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifications.is_empty()
+    }
+
+    /// When the batch should be sent, `None` if there is nothing to send.
+    pub fn flush_deadline(&self, coalesce_window: Duration) -> Option<Instant> {
+        self.notifications.iter().map(|pending| pending.queued_at).min().map(|queued_at| queued_at + coalesce_window)
+    }
+
+    /// Renders and removes the notifications, except joins and leaves still within their window. Joins are merged
+    /// into one line, everything else gets a line of its own and messages only get split up if they exceed
+    /// Telegram's length limit. Lines start with `[prefix]` if given.
+    pub fn take_messages(
+        &mut self,
+        now: Instant,
+        coalesce_window: Duration,
+        settings: &NotificationSettings,
+        templates: &MessageTemplates,
+        prefix: Option<&str>) -> Vec<String> {
+        let (held, due): (Vec<PendingNotification>, Vec<PendingNotification>) = std::mem::take(&mut self.notifications)
+            .into_iter()
+            .partition(|pending| pending.is_held(now, coalesce_window));
+        self.notifications = held;
+        let notifications: Vec<Notification> = due.into_iter().map(|pending| pending.notification).collect();
+
+        let joined_names: Vec<&str> = notifications.iter()
+            .filter_map(|notification| match notification {
+                Notification::UserJoined {display_name} => Some(display_name.as_str()),
+                _ => None
+            })
+            .collect();
+
+        let mut lines = vec![];
+        let mut joins_rendered = false;
+        for notification in &notifications {
+            let line = match notification {
+                Notification::UserJoined {..} if joins_rendered => None,
+                Notification::UserJoined {..} if !settings.join.enabled => None,
+                Notification::UserJoined {..} => {
+                    joins_rendered = true;
+                    templates.render(Template::Join, &json!({
+                        "user": templates.localizer().list(&joined_names),
+                        "users": joined_names,
+                        "count": joined_names.len()
+                    }))
+                },
+                Notification::UserLeft {..} if !settings.leave.enabled => None,
+                Notification::UserLeft {display_name, online_duration} => templates.render(Template::Leave, &json!({
+                    "user": display_name,
+                    "duration": templates.localizer().format_duration(*online_duration),
+                    "duration_seconds": online_duration.as_secs()
+                })),
+                Notification::UserSwitchedChannel {..} if !settings.channel_switch.enabled => None,
+                Notification::UserSwitchedChannel {display_name, channel} => templates.render(Template::ChannelSwitch, &json!({
+                    "user": display_name,
+                    "channel": channel
+                })),
+                Notification::UserMuteChanged {..} if !settings.mute.enabled => None,
+                Notification::UserMuteChanged {display_name, muted, deafened} => templates.render(Template::Mute, &json!({
+                    "user": display_name,
+                    "state": match (deafened, muted) {
+                        (true, _) => "deafened",
+                        (false, true) => "muted",
+                        (false, false) => "unmuted"
+                    },
+                    "muted": muted,
+                    "deafened": deafened
+                })),
+                Notification::ChannelCreated {..} if !settings.channel_created.enabled => None,
+                Notification::ChannelCreated {channel} => templates.render(Template::ChannelCreated, &json!({"channel": channel})),
+                Notification::ChannelDeleted {..} if !settings.channel_deleted.enabled => None,
+                Notification::ChannelDeleted {channel} => templates.render(Template::ChannelDeleted, &json!({"channel": channel}))
+            };
+            lines.extend(line.map(|line| match prefix {
+                Some(prefix) => format!("[{}] {}", prefix, line),
+                None => line
+            }));
+        }
+
+        let mut messages: Vec<String> = vec![];
+        for line in lines {
+            match messages.last_mut() {
+                Some(message) if message.len() + line.len() < TELEGRAM_MAX_MESSAGE_LENGTH => {
+                    message.push('\n');
+                    message.push_str(&line);
+                },
+                _ => messages.push(line)
+            }
+        }
+        messages
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::i18n::Localizer;
+    use crate::settings::{NotificationKindSettings, TemplateSettings};
+
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    fn settings() -> NotificationSettings {
+        NotificationSettings { leave: NotificationKindSettings { enabled: true }, ..Default::default() }
+    }
+
+    fn templates() -> MessageTemplates {
+        MessageTemplates::new(&TemplateSettings::default(), Localizer::new("en").unwrap()).unwrap()
+    }
+
+    fn joined(name: &str) -> Notification {
+        Notification::UserJoined { display_name: name.to_string() }
+    }
+
+    fn left(name: &str) -> Notification {
+        Notification::UserLeft { display_name: name.to_string(), online_duration: Duration::from_secs(2) }
+    }
+
+    fn take_messages(batch: &mut NotificationBatch, now: Instant) -> Vec<String> {
+        batch.take_messages(now, WINDOW, &settings(), &templates(), None)
+    }
+
+    #[test]
+    fn cancels_join_and_leave_within_the_window() {
+        let start = Instant::now();
+        let mut batch = NotificationBatch::default();
+        batch.push(joined("alice"), start);
+        batch.push(left("alice"), start + Duration::from_secs(2));
+
+        assert!(batch.is_empty());
+        assert_eq!(batch.flush_deadline(WINDOW), None);
+    }
+
+    #[test]
+    fn holds_joins_across_a_flush_of_older_notifications() {
+        let start = Instant::now();
+        let mut batch = NotificationBatch::default();
+        batch.push(joined("alice"), start);
+        batch.push(joined("bob"), start + Duration::from_secs(4));
+
+        let messages = take_messages(&mut batch, start + WINDOW);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("alice") && !messages[0].contains("bob"));
+
+        // Bob's join is still within its own window, so leaving cancels it
+        batch.push(left("bob"), start + Duration::from_secs(6));
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn cancels_leave_and_rejoin_within_the_window() {
+        let start = Instant::now();
+        let mut batch = NotificationBatch::default();
+        batch.push(left("alice"), start);
+        batch.push(joined("alice"), start + Duration::from_secs(1));
+        assert!(batch.is_empty());
+
+        batch.push(joined("alice"), start + Duration::from_secs(10));
+        assert_eq!(batch.flush_deadline(WINDOW), Some(start + Duration::from_secs(10) + WINDOW));
+        assert!(take_messages(&mut batch, start + Duration::from_secs(12)).is_empty());
+        let messages = take_messages(&mut batch, start + Duration::from_secs(15));
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("alice"));
+        assert!(batch.is_empty());
+    }
+}
@@ -1,33 +1,116 @@
 use config::{Config, ConfigError};
 use serde_derive::Deserialize;
+use std::collections::BTreeMap;
 use std::env;
-use mumble_client_rs::MumbleClientConfig;
+use std::time::Duration;
+use mumble_client_rs::{CertificateFingerprint, ClientCertificate, MumbleClientConfig, MumbleClientError, ReconnectPolicy, ServerCertificateVerification};
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 #[serde(rename_all = "snake_case")]
 pub struct MumbleSettings {
+    /// Name shown in the pinned status message and used to tell servers apart, defaults to the server address.
+    pub server_name: Option<String>,
     pub server_address: String,
     pub server_port: u16,
     pub override_tls_server_name: Option<String>,
     #[serde(default)]
     pub insecure_disable_certificate_verification: bool,
+    /// SHA-256 fingerprint of the only server certificate trusted, takes precedence over `ca_file`.
+    pub certificate_fingerprint: Option<String>,
+    /// PEM file of the certificate authorities trusted instead of the system ones.
+    pub ca_file: Option<String>,
+    /// Trusts the certificate seen on the first connection, remembered in the state file, and refuses to connect
+    /// if it changes until the new fingerprint is set as `acknowledged_certificate_fingerprint`.
+    #[serde(default)]
+    pub trust_on_first_use: bool,
+    pub acknowledged_certificate_fingerprint: Option<String>,
     pub username: String,
     pub password: Option<String>,
+    /// Access tokens for entering password protected channels.
+    #[serde(default)]
+    pub access_tokens: Vec<String>,
+    pub connect_timeout_seconds: Option<u64>,
+    #[serde(default)]
+    pub filter_out_inferred_bot_users: bool,
+    /// Undeafens the bot and listens to every channel to show who is talking in the pinned status message and
+    /// count talk time for `weekly_talk_stats`.
     #[serde(default)]
-    pub filter_out_inferred_bot_users: bool
+    pub show_talking: bool,
+    /// Lets the bot connect as a registered user.
+    pub client_certificate: Option<ClientCertificateSettings>
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+#[serde(rename_all = "snake_case")]
+pub struct ClientCertificateSettings {
+    /// PEM certificate, generated on first start if it doesn't exist, or a PKCS#12 file ending in `.p12` / `.pfx`.
+    pub certificate_path: String,
+    /// PEM private key, defaults to the certificate file.
+    pub private_key_path: Option<String>,
+    /// Password of a PKCS#12 file.
+    pub password: Option<String>
+}
+
+impl ClientCertificateSettings {
+    fn load(&self, common_name: &str) -> Result<ClientCertificate, MumbleClientError> {
+        let is_pkcs12 = [".p12", ".pfx"].iter().any(|extension| self.certificate_path.to_lowercase().ends_with(extension));
+        if is_pkcs12 {
+            return ClientCertificate::from_pkcs12_file(&self.certificate_path, self.password.as_deref().unwrap_or_default());
+        }
+
+        let private_key_path = self.private_key_path.as_ref().unwrap_or(&self.certificate_path);
+        ClientCertificate::load_or_generate(&self.certificate_path, private_key_path, common_name)
+    }
 }
 
-impl Into<MumbleClientConfig> for MumbleSettings {
-    fn into(self) -> MumbleClientConfig {
-        MumbleClientConfig {
-            server_address: self.server_address,
-            server_port: self.server_port,
-            override_tls_server_name: self.override_tls_server_name,
-            insecure_disable_certificate_verification: self.insecure_disable_certificate_verification,
-            username: self.username,
-            password: self.password
+impl MumbleSettings {
+    pub fn display_name(&self) -> String {
+        self.server_name.clone().unwrap_or_else(|| self.server_address.clone())
+    }
+
+    fn server_certificate_verification(&self) -> Result<ServerCertificateVerification, MumbleClientError> {
+        if let Some(fingerprint) = &self.certificate_fingerprint {
+            return Ok(ServerCertificateVerification::Fingerprint(fingerprint.parse()?));
+        }
+        if let Some(ca_file) = &self.ca_file {
+            return Ok(ServerCertificateVerification::CaFile(ca_file.into()));
+        }
+        if self.trust_on_first_use {
+            let known = self.acknowledged_certificate_fingerprint.as_deref()
+                .map(str::parse::<CertificateFingerprint>)
+                .transpose()?;
+            return Ok(ServerCertificateVerification::TrustOnFirstUse { known });
         }
+
+        Ok(ServerCertificateVerification::SystemRoots)
+    }
+}
+
+impl TryFrom<MumbleSettings> for MumbleClientConfig {
+    type Error = MumbleClientError;
+
+    fn try_from(settings: MumbleSettings) -> Result<Self, Self::Error> {
+        let client_certificate = settings.client_certificate.as_ref()
+            .map(|certificate| certificate.load(&settings.username))
+            .transpose()?;
+        let server_certificate_verification = settings.server_certificate_verification()?;
+        Ok(MumbleClientConfig {
+            server_address: settings.server_address,
+            server_port: settings.server_port,
+            override_tls_server_name: settings.override_tls_server_name,
+            insecure_disable_certificate_verification: settings.insecure_disable_certificate_verification,
+            server_certificate_verification,
+            username: settings.username,
+            password: settings.password,
+            access_tokens: settings.access_tokens,
+            connect_timeout: Duration::from_secs(settings.connect_timeout_seconds.unwrap_or(30)),
+            receive_audio: settings.show_talking,
+            listen_to_all_channels: settings.show_talking,
+            client_certificate,
+            reconnect_policy: ReconnectPolicy::default()
+        })
     }
 }
 
@@ -35,8 +118,158 @@ impl Into<MumbleClientConfig> for MumbleSettings {
 #[allow(unused)]
 #[serde(rename_all = "snake_case")]
 pub struct TelegramSettings {
+    pub token: String,
+    #[serde(default)]
+    pub chats: Vec<ChatSettings>,
+    /// The single chat configured before `chats` existed, only used if `chats` is empty.
+    pub chat_id: Option<i64>,
+    /// Default language of the bot's messages, `en` (default) or `de`.
+    pub locale: Option<String>,
+    /// Minimum time between edits of the pinned status message, defaults to 3 seconds.
+    pub pinned_message_edit_interval_seconds: Option<u64>
+}
+
+/// Which way messages are bridged between a chat and mumble, if the bridge is enabled.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeDirection {
+    Disabled,
+    TelegramToMumble,
+    MumbleToTelegram,
+    #[default]
+    Both
+}
+
+impl BridgeDirection {
+    pub fn telegram_to_mumble(self) -> bool {
+        matches!(self, BridgeDirection::TelegramToMumble | BridgeDirection::Both)
+    }
+
+    pub fn mumble_to_telegram(self) -> bool {
+        matches!(self, BridgeDirection::MumbleToTelegram | BridgeDirection::Both)
+    }
+}
+
+/// A chat the bot posts to. Unset values fall back to the top level `telegram.locale`, `notifications` and
+/// `templates`, templates are overridden one by one.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+#[serde(rename_all = "snake_case")]
+pub struct ChatSettings {
     pub chat_id: i64,
-    pub token: String
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub bridge: BridgeDirection,
+    /// Whether the chat gets a pinned status message, defaults to true.
+    pub pinned_status: Option<bool>,
+    pub notifications: Option<NotificationSettings>,
+    #[serde(default)]
+    pub templates: TemplateSettings,
+    /// Posts into a forum topic per mumble channel, the chat has to be a supergroup with topics enabled.
+    #[serde(default)]
+    pub forum_topics: bool,
+    /// Names of the servers in `mumble_servers` relayed to this chat, defaults to all of them.
+    pub servers: Option<Vec<String>>,
+    /// Posts who talked the most every Monday, for the servers with `show_talking` enabled.
+    #[serde(default)]
+    pub weekly_talk_stats: bool
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[allow(unused)]
+#[serde(rename_all = "snake_case")]
+pub struct BridgeSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Name of the mumble channel Telegram messages are posted to, defaults to the root channel.
+    pub mumble_channel: Option<String>
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+#[serde(rename_all = "snake_case")]
+pub struct UsernameMapping {
+    pub mumble: String,
+    /// Telegram mention for the user, e.g. `@username`.
+    pub telegram: String
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[allow(unused)]
+#[serde(rename_all = "snake_case")]
+pub struct NotificationKindSettings {
+    #[serde(default)]
+    pub enabled: bool
+}
+
+/// Which mumble events are announced in Telegram. Without a `notifications` section only joins are announced.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+#[serde(rename_all = "snake_case", default)]
+pub struct NotificationSettings {
+    pub join: NotificationKindSettings,
+    pub leave: NotificationKindSettings,
+    pub channel_switch: NotificationKindSettings,
+    pub mute: NotificationKindSettings,
+    pub channel_created: NotificationKindSettings,
+    pub channel_deleted: NotificationKindSettings,
+    /// Notifications within this many seconds are sent as one message, defaults to 5 seconds.
+    pub coalesce_window_seconds: Option<u64>
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            join: NotificationKindSettings { enabled: true },
+            leave: NotificationKindSettings::default(),
+            channel_switch: NotificationKindSettings::default(),
+            mute: NotificationKindSettings::default(),
+            channel_created: NotificationKindSettings::default(),
+            channel_deleted: NotificationKindSettings::default(),
+            coalesce_window_seconds: None
+        }
+    }
+}
+
+/// Handlebars templates overriding the default messages, the available variables are listed per template.
+/// Notifications are plain text, the pinned status and `/who` are HTML.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[allow(unused)]
+#[serde(rename_all = "snake_case")]
+pub struct TemplateSettings {
+    /// `user` (all joined names as one list), `users`, `count`
+    pub join: Option<String>,
+    /// `user`, `duration`, `duration_seconds`
+    pub leave: Option<String>,
+    /// `user`, `channel`
+    pub channel_switch: Option<String>,
+    /// `user`, `state`, `muted`, `deafened`
+    pub mute: Option<String>,
+    /// `channel`
+    pub channel_created: Option<String>,
+    /// `channel`
+    pub channel_deleted: Option<String>,
+    /// HTML, `count`, `users`, `tree`, `connected_for`, `server.name`, `server.release`,
+    /// `server.max_users`
+    pub pinned_status: Option<String>,
+    /// `count`, `tree`
+    pub who: Option<String>
+}
+
+impl TemplateSettings {
+    /// These templates, with the ones not set taken from `defaults`.
+    pub fn or(&self, defaults: &TemplateSettings) -> TemplateSettings {
+        TemplateSettings {
+            join: self.join.clone().or_else(|| defaults.join.clone()),
+            leave: self.leave.clone().or_else(|| defaults.leave.clone()),
+            channel_switch: self.channel_switch.clone().or_else(|| defaults.channel_switch.clone()),
+            mute: self.mute.clone().or_else(|| defaults.mute.clone()),
+            channel_created: self.channel_created.clone().or_else(|| defaults.channel_created.clone()),
+            channel_deleted: self.channel_deleted.clone().or_else(|| defaults.channel_deleted.clone()),
+            pinned_status: self.pinned_status.clone().or_else(|| defaults.pinned_status.clone()),
+            who: self.who.clone().or_else(|| defaults.who.clone())
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,8 +277,56 @@ pub struct TelegramSettings {
 #[serde(rename_all = "snake_case")]
 pub struct Settings {
     pub state_file_path: String,
-    pub mumble: MumbleSettings,
-    pub telegram: TelegramSettings
+    #[serde(default)]
+    pub mumble_servers: BTreeMap<String, MumbleSettings>,
+    /// The single server configured before `mumble_servers` existed, only used if `mumble_servers` is empty.
+    pub mumble: Option<MumbleSettings>,
+    pub telegram: TelegramSettings,
+    #[serde(default)]
+    pub bridge: BridgeSettings,
+    #[serde(default)]
+    pub username_map: Vec<UsernameMapping>,
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    #[serde(default)]
+    pub templates: TemplateSettings
+}
+
+impl Settings {
+    /// The configured chats with the top level defaults applied.
+    pub fn chats(&self) -> Vec<ChatSettings> {
+        let mut chats = self.telegram.chats.clone();
+        if chats.is_empty() {
+            chats.extend(self.telegram.chat_id.map(|chat_id| ChatSettings {
+                chat_id,
+                locale: None,
+                bridge: BridgeDirection::Both,
+                pinned_status: None,
+                notifications: None,
+                templates: TemplateSettings::default(),
+                forum_topics: false,
+                servers: None,
+                weekly_talk_stats: false
+            }));
+        }
+
+        for chat in &mut chats {
+            chat.locale = chat.locale.take().or_else(|| self.telegram.locale.clone());
+            chat.notifications = chat.notifications.take().or_else(|| Some(self.notifications.clone()));
+            chat.templates = chat.templates.or(&self.templates);
+            chat.servers = chat.servers.take().or_else(|| Some(self.mumble_servers().into_keys().collect()));
+        }
+        chats
+    }
+
+    /// The configured servers by name, a lone `mumble` section is named `default`.
+    pub fn mumble_servers(&self) -> BTreeMap<String, MumbleSettings> {
+        let mut servers = self.mumble_servers.clone();
+        if servers.is_empty() {
+            servers.extend(self.mumble.clone().map(|settings| ("default".to_string(), settings)));
+        }
+        servers
+    }
 }
 
 impl SettingsProvider for Settings {
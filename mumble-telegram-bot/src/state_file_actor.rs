@@ -1,26 +1,84 @@
+use std::collections::HashMap;
 use std::env;
 use std::path::PathBuf;
+use std::time::Duration;
+use log::error;
 use serde_derive::{Deserialize, Serialize};
 use tokio::sync::{oneshot, mpsc};
+use tokio::time::{self, MissedTickBehavior};
+
+/// How often changes made with [StateFileActorHandle::update_state_later] are written to the state file.
+const DEFERRED_WRITE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PersistentState {
-    pub mumble_rolling_state_message_id: Option<i32>
+    /// Pinned status message of the single chat from before multiple chats were supported, see
+    /// [PersistentState::migrate_legacy_pinned_message].
+    #[serde(default, skip_serializing)]
+    pub mumble_rolling_state_message_id: Option<i32>,
+    /// Pinned status message ids keyed by chat id.
+    #[serde(default)]
+    pub pinned_status_message_ids: HashMap<i64, i32>,
+    /// Telegram mentions users linked to their mumble name via `/link`, keyed by mumble name.
+    #[serde(default)]
+    pub username_links: HashMap<String, String>,
+    /// Forum topics created for mumble channels, keyed by chat id, server name and channel id.
+    #[serde(default)]
+    pub forum_topics: HashMap<i64, HashMap<String, HashMap<u32, ForumTopicState>>>,
+    /// Server certificate fingerprints trusted on first use, keyed by server name.
+    #[serde(default)]
+    pub server_certificate_fingerprints: HashMap<String, String>,
+    #[serde(default)]
+    pub weekly_talk_time: WeeklyTalkTime
+}
+
+/// Talk time since the start of the week, until it is posted as the weekly stats.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct WeeklyTalkTime {
+    /// Unix timestamp of the Monday the week started, 0 before the first week.
+    pub week_start: u64,
+    /// Milliseconds talked keyed by server name and mumble name.
+    pub talk_time_millis: HashMap<String, HashMap<String, u64>>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForumTopicState {
+    pub message_thread_id: i32,
+    /// Channel name the topic was last named after.
+    pub name: String
+}
+
+impl PersistentState {
+    /// Assigns the pinned message of a state file from before multiple chats were supported to the given chat.
+    pub fn migrate_legacy_pinned_message(&mut self, chat_id: i64) {
+        if let Some(message_id) = self.mumble_rolling_state_message_id.take() {
+            self.pinned_status_message_ids.entry(chat_id).or_insert(message_id);
+        }
+    }
 }
 
 struct StateFileActor {
     receiver: mpsc::Receiver<StateFileActorMessage>,
     state_file_location: PathBuf,
-    state_snapshot: Option<PersistentState>
+    state_snapshot: Option<PersistentState>,
+    /// The snapshot has changes which weren't written to the state file yet.
+    unsaved_changes: bool
 }
 
 pub enum StateFileActorMessage {
-    SaveState {
-        respond_to: oneshot::Sender<()>,
-        state: PersistentState
-    },
     GetState {
         respond_to: oneshot::Sender<PersistentState>
+    },
+    UpdateState {
+        respond_to: oneshot::Sender<PersistentState>,
+        update: Box<dyn FnOnce(&mut PersistentState) + Send>
+    },
+    UpdateStateLater {
+        respond_to: oneshot::Sender<()>,
+        update: Box<dyn FnOnce(&mut PersistentState) + Send>
+    },
+    WriteUnsavedChanges {
+        respond_to: oneshot::Sender<()>
     }
 }
 
@@ -29,38 +87,72 @@ impl StateFileActor {
         Self {
             receiver,
             state_file_location,
-            state_snapshot: None
+            state_snapshot: None,
+            unsaved_changes: false
+        }
+    }
+
+    fn load_state(&mut self) -> PersistentState {
+        if let Some(state_snapshot) = self.state_snapshot.as_ref() {
+            return state_snapshot.clone();
+        }
+
+        if std::fs::try_exists(&self.state_file_location).is_ok_and(|exists| exists) {
+            let state = std::fs::read_to_string(&self.state_file_location).unwrap();
+            let state = serde_json::from_str::<PersistentState>(&state)
+                .expect("State is corrupted and cannot be deserialised");
+            self.state_snapshot = Some(state.clone());
+            state
+        }
+
+        else {
+            let state = PersistentState::default();
+            self.write_state(state.clone());
+            state
+        }
+    }
+
+    /// Keeps the state in memory even if it can't be persisted, so that the bot continues to work. The file is
+    /// replaced atomically so that a crash can't leave it truncated.
+    fn write_state(&mut self, state: PersistentState) {
+        let mut temporary_location = self.state_file_location.clone().into_os_string();
+        temporary_location.push(".tmp");
+        let result = std::fs::write(&temporary_location, serde_json::to_string_pretty(&state).unwrap())
+            .and_then(|_| std::fs::rename(&temporary_location, &self.state_file_location));
+        if let Err(err) = result {
+            error!("Unable to write state file {}: {}", self.state_file_location.display(), err);
+        }
+        self.state_snapshot = Some(state);
+        self.unsaved_changes = false;
+    }
+
+    fn write_unsaved_changes(&mut self) {
+        if let Some(state) = self.state_snapshot.clone().filter(|_| self.unsaved_changes) {
+            self.write_state(state);
         }
     }
 
     async fn handle_message(&mut self, msg: StateFileActorMessage) {
         match msg {
             StateFileActorMessage::GetState {respond_to} => {
-                if let Some(state_snapshot) = self.state_snapshot.as_ref() {
-                    respond_to.send(state_snapshot.clone()).unwrap();
-                    return;
-                }
-
-                if std::fs::try_exists(&self.state_file_location).is_ok_and(|exists| exists) {
-                    let state = std::fs::read_to_string(&self.state_file_location).unwrap();
-                    let state = serde_json::from_str::<PersistentState>(&state)
-                        .expect("State is corrupted and cannot be deserialised");
-                    respond_to.send(state).unwrap();
-                }
-
-                else {
-                    let state = PersistentState {
-                        mumble_rolling_state_message_id: None
-                    };
-                    self.state_snapshot = Some(state.clone());
-                    std::fs::write(&self.state_file_location, serde_json::to_string_pretty(&state).unwrap()).unwrap();
-                    respond_to.send(state).unwrap();
-                }
+                let _ = respond_to.send(self.load_state());
             },
-            StateFileActorMessage::SaveState {respond_to, state} => {
-                std::fs::write(&self.state_file_location, serde_json::to_string_pretty(&state).unwrap()).unwrap();
+            StateFileActorMessage::UpdateState {respond_to, update} => {
+                let mut state = self.load_state();
+                update(&mut state);
+                self.write_state(state.clone());
+                let _ = respond_to.send(state);
+            },
+            StateFileActorMessage::UpdateStateLater {respond_to, update} => {
+                let mut state = self.load_state();
+                update(&mut state);
                 self.state_snapshot = Some(state);
-                respond_to.send(()).unwrap();
+                self.unsaved_changes = true;
+                let _ = respond_to.send(());
+            },
+            StateFileActorMessage::WriteUnsavedChanges {respond_to} => {
+                self.write_unsaved_changes();
+                let _ = respond_to.send(());
             }
         }
     }
@@ -79,9 +171,18 @@ fn resolve_absolute_path(path: &str) -> PathBuf {
 }
 
 async fn run_actor(mut actor: StateFileActor) {
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg).await;
+    let mut deferred_write = time::interval(DEFERRED_WRITE_INTERVAL);
+    deferred_write.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            msg = actor.receiver.recv() => match msg {
+                Some(msg) => actor.handle_message(msg).await,
+                None => break
+            },
+            _ = deferred_write.tick() => actor.write_unsaved_changes()
+        }
     }
+    actor.write_unsaved_changes();
 }
 
 #[derive(Clone)]
@@ -108,14 +209,39 @@ impl StateFileActorHandle {
         recv.await.expect("Actor has been killed")
     }
 
-    pub async fn save_state(&self, state: PersistentState) {
+    /// Applies `update` to the current state and persists the result, without racing other writers.
+    pub async fn update_state(&self, update: impl FnOnce(&mut PersistentState) + Send + 'static) -> PersistentState {
         let (send, recv) = oneshot::channel();
-        let msg = StateFileActorMessage::SaveState {
+        let msg = StateFileActorMessage::UpdateState {
             respond_to: send,
-            state
+            update: Box::new(update)
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor has been killed")
+    }
+
+    /// Applies `update` to the current state like [update_state](Self::update_state), but only writes the state
+    /// file every minute. For frequent updates which may be lost if the bot crashes.
+    pub async fn update_state_later(&self, update: impl FnOnce(&mut PersistentState) + Send + 'static) {
+        let (send, recv) = oneshot::channel();
+        let msg = StateFileActorMessage::UpdateStateLater {
+            respond_to: send,
+            update: Box::new(update)
         };
 
         let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor has been killed");
+        recv.await.expect("Actor has been killed")
+    }
+
+    /// Writes the changes made with [update_state_later](Self::update_state_later) right away, before shutting down.
+    pub async fn write_unsaved_changes(&self) {
+        let (send, recv) = oneshot::channel();
+        let msg = StateFileActorMessage::WriteUnsavedChanges {
+            respond_to: send
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor has been killed")
     }
 }
\ No newline at end of file
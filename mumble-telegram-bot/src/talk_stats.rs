@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use fluent_bundle::FluentArgs;
+use log::info;
+use tokio::sync::oneshot;
+use tokio::time;
+use crate::formatting;
+use crate::settings::UsernameMapping;
+use crate::state_file_actor::{StateFileActorHandle, WeeklyTalkTime};
+use crate::telegram_sender_actor::TelegramSenderActorHandle;
+use crate::username_map::UsernameMap;
+
+const DAY_SECONDS: u64 = 86400;
+const WEEK_SECONDS: u64 = 7 * DAY_SECONDS;
+/// The unix epoch was a Thursday, weeks start on the Monday before.
+const EPOCH_WEEK_OFFSET_SECONDS: u64 = 3 * DAY_SECONDS;
+/// Users listed per server in the weekly stats.
+const MOST_TALKATIVE_USERS: usize = 5;
+
+/// Adds to the talk time of a user in the current week. Talk time is recorded after every transmission, so it's
+/// only written to the state file periodically.
+pub async fn record_talk_time(state_file_actor_handle: &StateFileActorHandle, server: &str, user: &str, duration: Duration) {
+    let (server, user) = (server.to_string(), user.to_string());
+    state_file_actor_handle.update_state_later(move |state| {
+        *state.weekly_talk_time.talk_time_millis.entry(server).or_default().entry(user).or_default() += duration.as_millis() as u64;
+    }).await;
+}
+
+/// Posts the most talkative users of the past week to the chats with `weekly_talk_stats` enabled every Monday at
+/// midnight UTC, or on startup if the bot wasn't running then.
+pub async fn run_weekly_talk_stats(
+    chats: Vec<TelegramSenderActorHandle>,
+    username_mappings: Vec<UsernameMapping>,
+    state_file_actor_handle: StateFileActorHandle) {
+    let chats: Vec<TelegramSenderActorHandle> = chats.into_iter().filter(|chat| chat.weekly_talk_stats()).collect();
+    if chats.is_empty() {
+        return;
+    }
+
+    loop {
+        let now = unix_time();
+        let week_start = week_start(now);
+        let finished_week = start_week(&state_file_actor_handle, week_start).await;
+        // Nothing was recorded before the first week started
+        if finished_week.week_start != 0 && finished_week.week_start < week_start {
+            info!("Posting talk stats of the week starting at {}", finished_week.week_start);
+            let username_map = UsernameMap::load(&username_mappings, &state_file_actor_handle).await;
+            for chat in &chats {
+                post_talk_stats(chat, &finished_week.talk_time_millis, &username_map).await;
+            }
+        }
+
+        time::sleep(Duration::from_secs(week_start + WEEK_SECONDS - now)).await;
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn week_start(unix_time: u64) -> u64 {
+    (unix_time + EPOCH_WEEK_OFFSET_SECONDS) / WEEK_SECONDS * WEEK_SECONDS - EPOCH_WEEK_OFFSET_SECONDS
+}
+
+/// Resets the talk time if the recorded week is over, returning the talk time recorded until then.
+async fn start_week(state_file_actor_handle: &StateFileActorHandle, week_start: u64) -> WeeklyTalkTime {
+    let (send, recv) = oneshot::channel();
+    state_file_actor_handle.update_state(move |state| {
+        let finished_week = match state.weekly_talk_time.week_start < week_start {
+            true => std::mem::replace(&mut state.weekly_talk_time, WeeklyTalkTime { week_start, ..Default::default() }),
+            false => WeeklyTalkTime::default()
+        };
+        let _ = send.send(finished_week);
+    }).await;
+    recv.await.unwrap_or_default()
+}
+
+async fn post_talk_stats(
+    chat: &TelegramSenderActorHandle,
+    talk_time_millis: &HashMap<String, HashMap<String, u64>>,
+    username_map: &UsernameMap) {
+    let localizer = chat.templates().localizer();
+    let servers: BTreeMap<&String, &HashMap<String, u64>> = talk_time_millis.iter()
+        .filter(|(server, users)| chat.relays_server(server) && !users.is_empty())
+        .collect();
+
+    let mut sections = vec![];
+    for (server, users) in &servers {
+        let mut users: Vec<(&String, &u64)> = users.iter().collect();
+        users.sort_by(|(a_name, a_millis), (b_name, b_millis)| b_millis.cmp(a_millis).then_with(|| a_name.cmp(b_name)));
+        let lines: Vec<String> = users.into_iter()
+            .take(MOST_TALKATIVE_USERS)
+            .enumerate()
+            .map(|(index, (name, millis))| {
+                let mut args = FluentArgs::new();
+                args.set("rank", index + 1);
+                args.set("user", formatting::escape_html(&username_map.display_name(name)));
+                args.set("duration", localizer.format_duration(Duration::from_millis(*millis)));
+                localizer.message("talk-stats-entry", Some(&args))
+            })
+            .collect();
+        let section = lines.join("\n");
+        match chat.server_prefix(server) {
+            Some(prefix) => sections.push(format!("<b>{}</b>\n{}", formatting::escape_html(prefix), section)),
+            None => sections.push(section)
+        }
+    }
+    let Some((server, _)) = servers.first_key_value() else {
+        return;
+    };
+
+    let message = format!("{}\n\n{}", localizer.message("talk-stats-header", None), sections.join("\n\n"));
+    chat.send_telegram_html_message(server, message, None).await
+}
@@ -1,38 +1,312 @@
+use fluent_bundle::FluentArgs;
+use log::{debug, error, warn};
+use serde_json::json;
 use teloxide::{Bot, RequestError};
-use teloxide::types::Update;
+use teloxide::net::Download;
+use teloxide::types::{ParseMode, Update, User};
 use teloxide::prelude::*;
 use teloxide::utils::command::BotCommands;
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use crate::{formatting, inline_images, username_map};
+use crate::channel_tree::ChannelNode;
+use crate::i18n::Localizer;
 use crate::mumble_actor::MumbleActorHandle;
-use crate::settings::TelegramSettings;
+use crate::settings::{BridgeSettings, TelegramSettings, UsernameMapping};
+use crate::state_file_actor::StateFileActorHandle;
+use crate::telegram_sender_actor::{TelegramSenderActorHandle, REFRESH_STATUS_CALLBACK_DATA};
+use crate::templates::{MessageTemplates, Template};
+use crate::username_map::UsernameMap;
 
+/// Descriptions are looked up in the locale as `command-<name>`.
 #[derive(BotCommands, Clone)]
-#[command(rename_rule = "lowercase", description = "The following commands are supported:")]
+#[command(rename_rule = "lowercase")]
 enum TelegramCommand {
-    #[command(description = "Display this help text")]
-    Help
+    Help,
+    Who,
+    Link(String)
 }
 
-async fn commands_handler(bot: Bot, msg: Message, cmd: TelegramCommand, _mumble: MumbleActorHandle) -> Result<(), RequestError> {
+fn help_text(localizer: &Localizer) -> String {
+    let mut lines = vec![localizer.message("help-header", None), String::new()];
+    for command in TelegramCommand::bot_commands() {
+        let name = command.command.trim_start_matches('/');
+        lines.push(format!("/{} — {}", name, localizer.message(&format!("command-{}", name), None)));
+    }
+    lines.join("\n")
+}
+
+async fn commands_handler(
+    bot: Bot,
+    msg: Message,
+    cmd: TelegramCommand,
+    mumble_servers: Vec<MumbleActorHandle>,
+    username_mappings: Vec<UsernameMapping>,
+    state_file_actor_handle: StateFileActorHandle,
+    chat: TelegramSenderActorHandle) -> Result<(), RequestError> {
+    let templates = chat.templates();
     match cmd {
         TelegramCommand::Help => {
-            bot.send_message(msg.chat.id, TelegramCommand::descriptions().to_string()).await?;
+            let mut request = bot.send_message(msg.chat.id, help_text(templates.localizer()));
+            // Answer in the forum topic the command was sent in
+            request.message_thread_id = msg.thread_id;
+            request.await?;
+            Ok(())
+        },
+        TelegramCommand::Who => {
+            let mut sections = vec![];
+            for mumble in relayed_servers(&mumble_servers, &chat) {
+                let Some(section) = list_online_users(mumble, &username_mappings, &state_file_actor_handle, templates).await else {
+                    continue;
+                };
+                match chat.server_prefix(mumble.server()) {
+                    Some(prefix) => sections.push(format!("<b>{}</b>\n{}", formatting::escape_html(prefix), section)),
+                    None => sections.push(section)
+                }
+            }
+            for message in formatting::split_html_lines(&sections.join("\n\n"), formatting::TELEGRAM_MAX_MESSAGE_LENGTH) {
+                let mut request = bot.send_message(msg.chat.id, message).parse_mode(ParseMode::Html);
+                request.message_thread_id = msg.thread_id;
+                request.await?;
+            }
+            Ok(())
+        },
+        TelegramCommand::Link(mumble_name) => {
+            let reply = link_username(&msg, mumble_name.trim(), &username_mappings, &state_file_actor_handle, templates.localizer()).await;
+            bot.send_message(msg.chat.id, reply).reply_to_message_id(msg.id).await?;
             Ok(())
         }
     }
 }
 
-async fn run_telegram_bot_actor(settings: TelegramSettings, mumble_actor_handle: MumbleActorHandle) {
-    let handler = Update::filter_message()
+async fn list_online_users(
+    mumble: &MumbleActorHandle,
+    username_mappings: &[UsernameMapping],
+    state_file_actor_handle: &StateFileActorHandle,
+    templates: &MessageTemplates) -> Option<String> {
+    let users = mumble.get_active_users().await;
+    let count = users.len();
+    let username_map = UsernameMap::load(username_mappings, state_file_actor_handle).await;
+    let tree = ChannelNode::build(mumble.get_channels().await, users)
+        .map(|channel_tree| channel_tree.to_telegram_html(&username_map, templates.localizer()))
+        .unwrap_or_default();
+
+    templates.render(Template::Who, &json!({"count": count, "tree": tree}))
+}
+
+async fn link_username(
+    msg: &Message,
+    mumble_name: &str,
+    username_mappings: &[UsernameMapping],
+    state_file_actor_handle: &StateFileActorHandle,
+    localizer: &Localizer) -> String {
+    let Some(telegram_username) = msg.from().and_then(|user| user.username.clone()) else {
+        return localizer.message("link-missing-username", None);
+    };
+    if mumble_name.is_empty() {
+        return localizer.message("link-usage", None);
+    }
+    if username_mappings.iter().any(|mapping| mapping.mumble == mumble_name) {
+        let mut args = FluentArgs::new();
+        args.set("mumble_name", mumble_name);
+        return localizer.message("link-configured", Some(&args));
+    }
+
+    let mention = format!("@{}", telegram_username);
+    let (send, recv) = oneshot::channel();
+    let (link_name, link_mention) = (mumble_name.to_string(), mention.clone());
+    state_file_actor_handle.update_state(move |state| {
+        let _ = send.send(username_map::link_username(&mut state.username_links, &link_name, &link_mention));
+    }).await;
+
+    let mut args = FluentArgs::new();
+    match recv.await.expect("Actor has been killed") {
+        Ok(()) => {
+            args.set("mention", mention);
+            localizer.message("link-success", Some(&args))
+        },
+        Err(_) => {
+            args.set("mumble_name", mumble_name);
+            localizer.message("link-taken", Some(&args))
+        }
+    }
+}
+
+/// The servers whose messages are relayed to the chat.
+fn relayed_servers<'a>(mumble_servers: &'a [MumbleActorHandle], chat: &'a TelegramSenderActorHandle) -> impl Iterator<Item = &'a MumbleActorHandle> {
+    mumble_servers.iter().filter(|mumble| chat.relays_server(mumble.server()))
+}
+
+async fn refresh_status_handler(
+    bot: Bot,
+    query: CallbackQuery,
+    mumble_servers: Vec<MumbleActorHandle>,
+    chat: TelegramSenderActorHandle) -> Result<(), RequestError> {
+    for mumble in relayed_servers(&mumble_servers, &chat) {
+        chat.update_pinned_mumble_status_message(mumble.server(), mumble.get_status().await).await;
+    }
+    bot.answer_callback_query(query.id).await?;
+    Ok(())
+}
+
+/// The name bridged messages are attributed to, preferring the sender's mapped mumble name.
+async fn bridged_sender_name(sender: &User, username_mappings: &[UsernameMapping], state_file_actor_handle: &StateFileActorHandle) -> String {
+    let username_map = UsernameMap::load(username_mappings, state_file_actor_handle).await;
+    sender.username.as_deref()
+        .and_then(|username| username_map.mumble_name(username))
+        .map(str::to_string)
+        .unwrap_or_else(|| sender.full_name())
+}
+
+async fn bridge_message_handler(
+    msg: Message,
+    text: String,
+    mumble_servers: Vec<MumbleActorHandle>,
+    chat: TelegramSenderActorHandle,
+    bridge_settings: BridgeSettings,
+    username_mappings: Vec<UsernameMapping>,
+    state_file_actor_handle: StateFileActorHandle) -> Result<(), RequestError> {
+    let sender = match msg.from() {
+        Some(user) if !user.is_bot => user,
+        _ => return Ok(())
+    };
+
+    let sender_name = bridged_sender_name(sender, &username_mappings, &state_file_actor_handle).await;
+    let entities = msg.parse_entities().unwrap_or_default();
+    for mumble in relayed_servers(&mumble_servers, &chat) {
+        debug!("Relaying Telegram message from {} to mumble server {}", sender_name, mumble.server());
+        let server_state = mumble.get_server_state().await;
+        for message in formatting::telegram_to_mumble_messages(&sender_name, &text, &entities, &server_state) {
+            if let Err(err) = mumble.send_text_message(bridge_settings.mumble_channel.clone(), message).await {
+                error!("Failed to relay Telegram message to mumble server {}: {}", mumble.server(), err);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn bridge_photo_handler(
+    bot: Bot,
+    msg: Message,
+    mumble_servers: Vec<MumbleActorHandle>,
+    chat: TelegramSenderActorHandle,
+    bridge_settings: BridgeSettings,
+    username_mappings: Vec<UsernameMapping>,
+    state_file_actor_handle: StateFileActorHandle) -> Result<(), RequestError> {
+    let sender = match msg.from() {
+        Some(user) if !user.is_bot => user,
+        _ => return Ok(())
+    };
+    let Some(photo) = msg.photo().and_then(|sizes| sizes.last()) else {
+        return Ok(());
+    };
+
+    let mut targets = vec![];
+    for mumble in relayed_servers(&mumble_servers, &chat) {
+        let server_state = mumble.get_server_state().await;
+        match server_state.allow_html.unwrap_or(true) {
+            true => targets.push((mumble, server_state)),
+            false => warn!("Mumble server {} does not allow HTML, unable to relay Telegram photo", mumble.server())
+        }
+    }
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    let file = match bot.get_file(&photo.file.id).await {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Failed to look up Telegram photo: {}", err);
+            return Ok(());
+        }
+    };
+    let mut image_bytes = vec![];
+    if let Err(err) = bot.download_file(&file.path, &mut image_bytes).await {
+        error!("Failed to download Telegram photo: {}", err);
+        return Ok(());
+    }
+
+    let sender_name = bridged_sender_name(sender, &username_mappings, &state_file_actor_handle).await;
+    let mut prefix = format!("<b>[Telegram] {}:</b> ", formatting::escape_html(&sender_name));
+    if let Some(caption) = msg.caption() {
+        let entities = msg.parse_caption_entities().unwrap_or_default();
+        prefix.push_str(&formatting::telegram_to_mumble_html(caption, &entities));
+    }
+    prefix.push_str("<br/>");
+
+    for (mumble, server_state) in targets {
+        debug!("Relaying Telegram photo from {} to mumble server {}", sender_name, mumble.server());
+        let max_length = server_state.max_image_message_length.unwrap_or(0) as usize;
+        let (image_bytes, prefix) = (image_bytes.clone(), prefix.clone());
+        let encoded = tokio::task::spawn_blocking(move || inline_images::encode_image_for_mumble(&image_bytes, &prefix, max_length)).await;
+        match encoded {
+            Ok(Ok(Some(message))) => {
+                if let Err(err) = mumble.send_text_message(bridge_settings.mumble_channel.clone(), message).await {
+                    error!("Failed to relay Telegram photo to mumble server {}: {}", mumble.server(), err);
+                }
+            },
+            Ok(Ok(None)) => warn!("Telegram photo could not be shrunk enough to fit the image message length of mumble server {}", mumble.server()),
+            Ok(Err(err)) => error!("Failed to re-encode Telegram photo: {}", err),
+            Err(err) => error!("Telegram photo encoding task failed: {}", err)
+        }
+    }
+    Ok(())
+}
+
+/// The chat a message was posted in, if it's one of the configured chats.
+fn find_chat(chats: &[TelegramSenderActorHandle], chat_id: ChatId) -> Option<TelegramSenderActorHandle> {
+    chats.iter().find(|chat| chat.chat_id() == chat_id).cloned()
+}
+
+async fn run_telegram_bot_actor(
+    settings: TelegramSettings,
+    bridge_settings: BridgeSettings,
+    username_mappings: Vec<UsernameMapping>,
+    mumble_servers: Vec<MumbleActorHandle>,
+    telegram_chats: Vec<TelegramSenderActorHandle>,
+    state_file_actor_handle: StateFileActorHandle) {
+    let message_handler = Update::filter_message()
+        .filter_map(|msg: Message, chats: Vec<TelegramSenderActorHandle>| find_chat(&chats, msg.chat.id))
         .branch(
             dptree::entry()
-                .filter(|msg: Message, settings: TelegramSettings| msg.chat.id == ChatId(settings.chat_id))
                 .filter_command::<TelegramCommand>()
                 .endpoint(commands_handler)
+        )
+        .branch(
+            dptree::entry()
+                .filter(|bridge_settings: BridgeSettings, chat: TelegramSenderActorHandle| {
+                    bridge_settings.enabled && chat.bridge().telegram_to_mumble()
+                })
+                .filter_map(|msg: Message| msg.text().filter(|text| !text.starts_with('/')).map(str::to_string))
+                .endpoint(bridge_message_handler)
+        )
+        .branch(
+            dptree::entry()
+                .filter(|bridge_settings: BridgeSettings, chat: TelegramSenderActorHandle| {
+                    bridge_settings.enabled && chat.bridge().telegram_to_mumble()
+                })
+                .filter(|msg: Message| msg.photo().is_some())
+                .endpoint(bridge_photo_handler)
         );
+    let callback_query_handler = Update::filter_callback_query()
+        .filter_map(|query: CallbackQuery, chats: Vec<TelegramSenderActorHandle>| {
+            query.message.and_then(|msg| find_chat(&chats, msg.chat.id))
+        })
+        .filter(|query: CallbackQuery| query.data.as_deref() == Some(REFRESH_STATUS_CALLBACK_DATA))
+        .endpoint(refresh_status_handler);
+    let handler = dptree::entry()
+        .branch(message_handler)
+        .branch(callback_query_handler);
 
     Dispatcher::builder(Bot::new(settings.token.clone()), handler)
-        .dependencies(dptree::deps![settings, mumble_actor_handle])
+        .dependencies(dptree::deps![
+            settings,
+            bridge_settings,
+            username_mappings,
+            mumble_servers,
+            telegram_chats,
+            state_file_actor_handle])
         .enable_ctrlc_handler()
         .build()
         .dispatch().await;
@@ -44,9 +318,21 @@ pub struct TelegramBotActorHandle {
 }
 
 impl TelegramBotActorHandle {
-    pub fn new(settings: TelegramSettings, mumble_actor_handle: MumbleActorHandle) -> (Self, JoinHandle<()>) {
-        let actor_task = tokio::spawn(run_telegram_bot_actor(settings, mumble_actor_handle));
+    pub fn new(
+        settings: TelegramSettings,
+        bridge_settings: BridgeSettings,
+        username_mappings: Vec<UsernameMapping>,
+        mumble_servers: Vec<MumbleActorHandle>,
+        telegram_chats: Vec<TelegramSenderActorHandle>,
+        state_file_actor_handle: StateFileActorHandle) -> (Self, JoinHandle<()>) {
+        let actor_task = tokio::spawn(run_telegram_bot_actor(
+            settings,
+            bridge_settings,
+            username_mappings,
+            mumble_servers,
+            telegram_chats,
+            state_file_actor_handle));
 
         (Self {}, actor_task)
     }
-}
\ No newline at end of file
+}
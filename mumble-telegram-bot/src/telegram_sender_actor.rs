@@ -1,29 +1,92 @@
-use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, warn};
 use tokio::sync::{oneshot, mpsc};
-use teloxide::{Bot, RequestError};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use teloxide::{ApiError, Bot, RequestError};
 use teloxide::prelude::*;
-use teloxide::types::{MessageId, Recipient};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode, Recipient};
 use tokio::task::JoinHandle;
-use mumble_client_rs::client::stateful_mumble_client::user::UserState;
-use crate::settings::TelegramSettings;
-use crate::state_file_actor::{PersistentState, StateFileActorHandle};
+use serde_json::json;
+use mumble_client_rs::client::stateful_mumble_client::channel::ChannelState;
+use crate::channel_tree::ChannelNode;
+use crate::formatting;
+use crate::mumble_actor::MumbleStatus;
+use crate::notifications::{Notification, NotificationBatch};
+use crate::settings::{BridgeDirection, ChatSettings, NotificationSettings, TelegramSettings, UsernameMapping};
+use crate::state_file_actor::{ForumTopicState, StateFileActorHandle};
+use crate::templates::{MessageTemplates, Template};
+use crate::username_map::UsernameMap;
+
+/// Callback data of the refresh button below the pinned status message.
+pub const REFRESH_STATUS_CALLBACK_DATA: &str = "refresh_status";
+
+/// Blue, one of the few colors Telegram allows for forum topic icons.
+const FORUM_TOPIC_ICON_COLOR: u32 = 0x6FB9F0;
+/// Telegram doesn't tell bots about unpinned messages, so whether the chat still has a pinned message is checked
+/// this often.
+const PINNED_STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(600);
 
 struct TelegramSenderActor {
     receiver: mpsc::Receiver<TelegramSenderActorMessage>,
     state_file_actor_handle: StateFileActorHandle,
     teloxide_bot: Bot,
     telegram_chat_id: i64,
-    pinned_mumble_status_message: Option<i32>
+    notification_settings: NotificationSettings,
+    templates: MessageTemplates,
+    username_mappings: Vec<UsernameMapping>,
+    /// Display names of the servers relayed to this chat, keyed by server name.
+    server_names: Arc<BTreeMap<String, String>>,
+    pinned_mumble_status_message: Option<i32>,
+    forum_topics_enabled: bool,
+    /// Forum topics keyed by server name and mumble channel id.
+    forum_topics: HashMap<String, HashMap<u32, ForumTopicState>>,
+    /// Notification batches keyed by server and the forum topic they are posted to, `None` being the general topic.
+    pending_notifications: HashMap<(String, Option<i32>), NotificationBatch>,
+    coalesce_window: Duration,
+    /// Latest status of each server, shown in the pinned message once the edit interval has passed.
+    server_statuses: BTreeMap<String, MumbleStatus>,
+    pinned_status_outdated: bool,
+    last_pinned_status_edit: Option<Instant>,
+    pinned_message_edit_interval: Duration
 }
 
 pub enum TelegramSenderActorMessage {
     SendTelegramMessage {
         respond_to: oneshot::Sender<()>,
-        message: String
+        message: String,
+        parse_mode: Option<ParseMode>,
+        server: String,
+        channel: Option<ChannelState>
+    },
+    QueueNotification {
+        respond_to: oneshot::Sender<()>,
+        notification: Notification,
+        server: String,
+        channel: Option<ChannelState>
+    },
+    SendTelegramPhoto {
+        respond_to: oneshot::Sender<()>,
+        photo: Vec<u8>,
+        html_caption: Option<String>,
+        server: String,
+        channel: Option<ChannelState>
+    },
+    SyncForumTopic {
+        respond_to: oneshot::Sender<()>,
+        server: String,
+        channel: ChannelState
+    },
+    CloseForumTopic {
+        respond_to: oneshot::Sender<()>,
+        server: String,
+        channel_id: u32
     },
     UpdatePinnedMumbleStatusMessage {
         respond_to: oneshot::Sender<()>,
-        active_users: Vec<UserState>
+        server: String,
+        status: MumbleStatus
     }
 }
 
@@ -31,111 +94,599 @@ impl TelegramSenderActor {
     fn new(
         receiver: mpsc::Receiver<TelegramSenderActorMessage>,
         state_file_actor_handle: StateFileActorHandle,
-        settings: &TelegramSettings) -> Self {
+        settings: &TelegramSettings,
+        chat: &ChatSettings,
+        server_names: Arc<BTreeMap<String, String>>,
+        templates: MessageTemplates,
+        username_mappings: Vec<UsernameMapping>) -> Self {
+        let notification_settings = chat.notifications.clone().unwrap_or_default();
         TelegramSenderActor {
             receiver,
             state_file_actor_handle,
             teloxide_bot: Bot::new(&settings.token),
-            telegram_chat_id: settings.chat_id,
-            pinned_mumble_status_message: None
+            telegram_chat_id: chat.chat_id,
+            coalesce_window: Duration::from_secs(notification_settings.coalesce_window_seconds.unwrap_or(5)),
+            notification_settings,
+            templates,
+            username_mappings,
+            server_names,
+            pinned_mumble_status_message: None,
+            forum_topics_enabled: chat.forum_topics,
+            forum_topics: HashMap::new(),
+            pending_notifications: HashMap::new(),
+            server_statuses: BTreeMap::new(),
+            pinned_status_outdated: false,
+            last_pinned_status_edit: None,
+            pinned_message_edit_interval: Duration::from_secs(settings.pinned_message_edit_interval_seconds.unwrap_or(3))
         }
     }
 
     async fn load_state(&mut self) {
         let state = self.state_file_actor_handle.get_state().await;
-        self.pinned_mumble_status_message = state.mumble_rolling_state_message_id;
+        self.pinned_mumble_status_message = state.pinned_status_message_ids.get(&self.telegram_chat_id).copied();
+        self.forum_topics = state.forum_topics.get(&self.telegram_chat_id).cloned().unwrap_or_default();
+    }
+
+    /// The forum topic of a channel, creating it if the channel doesn't have one yet. Messages not about a
+    /// particular channel and those about the root channel go to the general topic.
+    async fn message_thread_id(&mut self, server: &str, channel: Option<&ChannelState>) -> Option<i32> {
+        let channel = channel.filter(|channel| self.forum_topics_enabled && channel.parent_channel_id.is_some())?;
+        if let Some(topic) = self.forum_topics.get(server).and_then(|topics| topics.get(&channel.id)) {
+            return Some(topic.message_thread_id);
+        }
+        self.create_forum_topic(server, channel.id, &channel.name).await
+    }
+
+    fn forum_topic_name(&self, server: &str, channel_name: &str) -> String {
+        match server_prefix(&self.server_names, server) {
+            Some(prefix) => format!("[{}] {}", prefix, channel_name),
+            None => channel_name.to_string()
+        }
     }
 
-    async fn create_pinned_mumble_status_message(&mut self) {
-        let message: Message = self.teloxide_bot.send_message(
+    async fn create_forum_topic(&mut self, server: &str, channel_id: u32, channel_name: &str) -> Option<i32> {
+        // teloxide requires a custom emoji, an empty one gets the default icon
+        let create_result = self.teloxide_bot.create_forum_topic(
             Recipient::Id(ChatId(self.telegram_chat_id)),
-            "🎧 Mumble: 0 users online").await.unwrap();
+            self.forum_topic_name(server, channel_name),
+            FORUM_TOPIC_ICON_COLOR,
+            "")
+            .await;
+        let topic = match create_result {
+            Ok(topic) => topic,
+            Err(err) => {
+                error!("Failed to create forum topic for channel {}: {}", channel_name, err);
+                return None;
+            }
+        };
+
+        debug!("Created forum topic {} for channel {}", topic.message_thread_id, channel_name);
+        let topic_state = ForumTopicState { message_thread_id: topic.message_thread_id, name: channel_name.to_string() };
+        self.save_forum_topic(server, channel_id, topic_state).await;
+        Some(topic.message_thread_id)
+    }
+
+    /// Replaces a forum topic that was deleted in Telegram with a new one for the same channel.
+    async fn recreate_forum_topic(&mut self, server: &str, message_thread_id: i32) -> Option<i32> {
+        let (channel_id, topic) = self.forum_topics.get(server)?
+            .iter()
+            .find(|(_, topic)| topic.message_thread_id == message_thread_id)
+            .map(|(channel_id, topic)| (*channel_id, topic.clone()))?;
+        warn!("Forum topic {} of channel {} no longer exists, recreating it", message_thread_id, topic.name);
+        self.remove_forum_topic(server, channel_id).await;
+        self.create_forum_topic(server, channel_id, &topic.name).await
+    }
+
+    /// Closes the topic of a deleted channel, keeping its messages. A channel with the same name gets a new topic.
+    async fn close_forum_topic(&mut self, server: &str, channel_id: u32) {
+        let Some(topic) = self.remove_forum_topic(server, channel_id).await else {
+            return;
+        };
+        let close_result = self.teloxide_bot.close_forum_topic(Recipient::Id(ChatId(self.telegram_chat_id)), topic.message_thread_id).await;
+        if let Err(err) = close_result {
+            error!("Failed to close forum topic of channel {}: {}", topic.name, err);
+        }
+    }
+
+    /// Creates the topic of a new channel or renames it after the channel.
+    async fn sync_forum_topic(&mut self, server: &str, channel: ChannelState) {
+        if !self.forum_topics_enabled || channel.parent_channel_id.is_none() {
+            return;
+        }
+
+        let Some(topic) = self.forum_topics.get(server).and_then(|topics| topics.get(&channel.id)).cloned() else {
+            self.create_forum_topic(server, channel.id, &channel.name).await;
+            return;
+        };
+        if topic.name == channel.name {
+            return;
+        }
+
+        let edit_result = self.teloxide_bot.edit_forum_topic(Recipient::Id(ChatId(self.telegram_chat_id)), topic.message_thread_id)
+            .name(self.forum_topic_name(server, &channel.name))
+            .await;
+        match edit_result {
+            Ok(_) => self.save_forum_topic(server, channel.id, ForumTopicState { name: channel.name, ..topic }).await,
+            Err(err) => error!("Failed to rename forum topic of channel {}: {}", channel.name, err)
+        }
+    }
+
+    async fn save_forum_topic(&mut self, server: &str, channel_id: u32, topic: ForumTopicState) {
+        self.forum_topics.entry(server.to_string()).or_default().insert(channel_id, topic.clone());
+        let (chat_id, server) = (self.telegram_chat_id, server.to_string());
+        self.state_file_actor_handle.update_state(move |state| {
+            state.forum_topics.entry(chat_id).or_default().entry(server).or_default().insert(channel_id, topic);
+        }).await;
+    }
+
+    async fn remove_forum_topic(&mut self, server: &str, channel_id: u32) -> Option<ForumTopicState> {
+        let topic = self.forum_topics.get_mut(server)?.remove(&channel_id)?;
+        let (chat_id, server) = (self.telegram_chat_id, server.to_string());
+        self.state_file_actor_handle.update_state(move |state| {
+            if let Some(topics) = state.forum_topics.get_mut(&chat_id).and_then(|servers| servers.get_mut(&server)) {
+                topics.remove(&channel_id);
+            }
+        }).await;
+        Some(topic)
+    }
+
+    fn refresh_keyboard(&self) -> InlineKeyboardMarkup {
+        let label = self.templates.localizer().message("pinned-status-refresh", None);
+        InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(label, REFRESH_STATUS_CALLBACK_DATA)]])
+    }
+
+    /// Posts and pins a new status message, unpinning the one it replaces.
+    async fn create_pinned_mumble_status_message(&mut self, text: String, stale_message_id: Option<i32>) {
+        let send_result = self.teloxide_bot.send_message(
+            Recipient::Id(ChatId(self.telegram_chat_id)),
+            text)
+            .parse_mode(ParseMode::Html)
+            .reply_markup(self.refresh_keyboard())
+            .await;
+        let message = match send_result {
+            Ok(message) => message,
+            Err(err) => {
+                error!("Failed to send pinned status message: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = self.teloxide_bot.pin_chat_message(message.chat.id, message.id).disable_notification(true).await {
+            error!("Failed to pin status message: {}", err);
+        }
+        if let Some(stale_message_id) = stale_message_id {
+            // Fails if the stale message was deleted, in which case it's no longer pinned anyway
+            let _ = self.teloxide_bot.unpin_chat_message(message.chat.id).message_id(MessageId(stale_message_id)).await;
+        }
 
-        self.teloxide_bot.pin_chat_message(message.chat.id, message.id).await.unwrap();
         self.pinned_mumble_status_message = Some(message.id.0);
-        self.state_file_actor_handle.save_state(PersistentState {mumble_rolling_state_message_id: Some(message.id.0)}).await;
+        let chat_id = self.telegram_chat_id;
+        self.state_file_actor_handle.update_state(move |state| {
+            state.pinned_status_message_ids.insert(chat_id, message.id.0);
+        }).await;
+    }
+
+    /// Pins the status message again if the chat has no pinned message at all, as it was unpinned without being
+    /// deleted. Telegram only tells which message was pinned last, so a status message pinned before others can't
+    /// be told apart from an unpinned one and is left alone.
+    async fn ensure_status_message_pinned(&mut self) {
+        let Some(message_id) = self.pinned_mumble_status_message else {
+            return;
+        };
+        let chat = match self.teloxide_bot.get_chat(Recipient::Id(ChatId(self.telegram_chat_id))).await {
+            Ok(chat) => chat,
+            Err(err) => {
+                error!("Failed to look up the pinned message: {}", err);
+                return;
+            }
+        };
+        if chat.pinned_message.is_some() {
+            return;
+        }
+
+        warn!("Pinned status message {} is no longer pinned, pinning it again", message_id);
+        let pin_result = self.teloxide_bot.pin_chat_message(chat.id, MessageId(message_id)).disable_notification(true).await;
+        if let Err(err) = pin_result {
+            // The message is most likely deleted, editing it finds out and recreates it
+            warn!("Failed to pin status message again: {}", err);
+            self.pinned_status_outdated = true;
+        }
+    }
+
+    /// Sends a message to a forum topic, recreating the topic if it was deleted in Telegram.
+    async fn send_message(&mut self, message: String, parse_mode: Option<ParseMode>, server: &str, message_thread_id: Option<i32>) {
+        debug!("Sending Message to configured channel: {}", message);
+        let mut send_result = self.send_message_to_thread(message.clone(), parse_mode, message_thread_id).await;
+        if let (Err(err), Some(message_thread_id)) = (&send_result, message_thread_id) {
+            if is_message_thread_not_found(err) {
+                let message_thread_id = self.recreate_forum_topic(server, message_thread_id).await;
+                send_result = self.send_message_to_thread(message, parse_mode, message_thread_id).await;
+            }
+        }
+        if let Err(err) = send_result {
+            error!("Failed to send message to Telegram: {}", err);
+        }
+    }
+
+    async fn send_message_to_thread(&self, message: String, parse_mode: Option<ParseMode>, message_thread_id: Option<i32>) -> Result<Message, RequestError> {
+        let mut request = self.teloxide_bot.send_message(
+            Recipient::Id(ChatId(self.telegram_chat_id)),
+            message);
+        request.parse_mode = parse_mode;
+        request.message_thread_id = message_thread_id;
+        request.await
+    }
+
+    /// Sends a photo to a forum topic, recreating the topic if it was deleted in Telegram.
+    async fn send_photo(&mut self, photo: Vec<u8>, html_caption: Option<String>, server: &str, message_thread_id: Option<i32>) {
+        debug!("Sending Photo to configured channel");
+        let mut send_result = self.send_photo_to_thread(photo.clone(), html_caption.clone(), message_thread_id).await;
+        if let (Err(err), Some(message_thread_id)) = (&send_result, message_thread_id) {
+            if is_message_thread_not_found(err) {
+                let message_thread_id = self.recreate_forum_topic(server, message_thread_id).await;
+                send_result = self.send_photo_to_thread(photo, html_caption, message_thread_id).await;
+            }
+        }
+        if let Err(err) = send_result {
+            error!("Failed to send photo to Telegram: {}", err);
+        }
+    }
+
+    async fn send_photo_to_thread(&self, photo: Vec<u8>, html_caption: Option<String>, message_thread_id: Option<i32>) -> Result<Message, RequestError> {
+        let mut request = self.teloxide_bot.send_photo(
+            Recipient::Id(ChatId(self.telegram_chat_id)),
+            InputFile::memory(photo));
+        request.message_thread_id = message_thread_id;
+        if let Some(caption) = html_caption {
+            request = request.caption(caption).parse_mode(ParseMode::Html);
+        }
+        request.await
+    }
+
+    fn notification_deadline(&self) -> Option<Instant> {
+        self.pending_notifications.values()
+            .filter_map(|batch| batch.flush_deadline(self.coalesce_window))
+            .min()
+    }
+
+    /// Sends the batches due by `now`.
+    async fn flush_notifications(&mut self, now: Instant) {
+        let due: Vec<(String, Option<i32>)> = self.pending_notifications.iter()
+            .filter(|(_, batch)| batch.flush_deadline(self.coalesce_window).is_some_and(|deadline| deadline <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in due {
+            let Some(batch) = self.pending_notifications.get_mut(&key) else {
+                continue;
+            };
+            let (server, message_thread_id) = &key;
+            let prefix = server_prefix(&self.server_names, server);
+            let messages = batch.take_messages(now, self.coalesce_window, &self.notification_settings, &self.templates, prefix);
+            if batch.is_empty() {
+                self.pending_notifications.remove(&key);
+            }
+            for message in messages {
+                self.send_message(message, None, server, *message_thread_id).await;
+            }
+        }
+    }
+
+    fn pinned_status_deadline(&self) -> Option<Instant> {
+        if !self.pinned_status_outdated {
+            return None;
+        }
+        Some(self.last_pinned_status_edit.map_or_else(Instant::now, |last_edit| last_edit + self.pinned_message_edit_interval))
+    }
+
+    fn render_server_status(&self, status: &MumbleStatus, username_map: &UsernameMap) -> Option<String> {
+        let localizer = self.templates.localizer();
+        let users: Vec<String> = status.users.iter().map(|u| formatting::escape_html(&username_map.display_name(&u.name))).collect();
+        let server = &status.server;
+        let connected_for = server.synced_at.map(|synced_at| localizer.format_duration(synced_at.elapsed())).unwrap_or_default();
+        let tree = ChannelNode::build(status.channels.clone(), status.users.clone())
+            .map(|channel_tree| channel_tree.to_telegram_html(username_map, localizer))
+            .unwrap_or_default();
+        self.templates.render(Template::PinnedStatus, &json!({
+            "count": users.len(),
+            "users": users,
+            "tree": tree,
+            "connected_for": connected_for,
+            "server": {
+                "name": formatting::escape_html(&status.server_name),
+                "release": server.server_info.as_ref().and_then(|info| info.release.as_deref()).map(formatting::escape_html).unwrap_or_default(),
+                "max_users": server.max_users
+            }
+        }))
+    }
+
+    /// Shows the status of every server relayed to the chat, one after another.
+    async fn flush_pinned_status(&mut self) {
+        if !self.pinned_status_outdated {
+            return;
+        }
+        self.pinned_status_outdated = false;
+        self.last_pinned_status_edit = Some(Instant::now());
+
+        let username_map = UsernameMap::load(&self.username_mappings, &self.state_file_actor_handle).await;
+        let sections: Vec<String> = self.server_statuses.values()
+            .filter_map(|status| self.render_server_status(status, &username_map))
+            .collect();
+        // The status can't be split over several messages, so whatever exceeds Telegram's limit is cut off
+        let mut messages = formatting::split_html_lines(&sections.join("\n\n"), formatting::TELEGRAM_MAX_MESSAGE_LENGTH);
+        if messages.is_empty() {
+            return;
+        }
+        let message = messages.swap_remove(0);
+
+        let Some(message_id) = self.pinned_mumble_status_message else {
+            self.create_pinned_mumble_status_message(message, None).await;
+            return;
+        };
+
+        let edit_result = self.teloxide_bot.edit_message_text(
+            Recipient::Id(ChatId(self.telegram_chat_id)),
+            MessageId(message_id),
+            message.clone())
+            .parse_mode(ParseMode::Html)
+            .reply_markup(self.refresh_keyboard())
+            .await;
+        match edit_result {
+            Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {},
+            Err(RequestError::Api(ApiError::MessageToEditNotFound | ApiError::MessageCantBeEdited)) => {
+                warn!("Pinned status message {} was deleted or can no longer be edited, recreating it", message_id);
+                self.create_pinned_mumble_status_message(message, Some(message_id)).await;
+            },
+            Err(err) => error!("Failed to update pinned status message: {}", err)
+        }
     }
 
     async fn handle_message(&mut self, msg: TelegramSenderActorMessage) {
         match msg {
-            TelegramSenderActorMessage::SendTelegramMessage {respond_to, message} => {
-                debug!("Sending Message to configured channel: {}", message);
-                let _send_result: Result<Message, RequestError> =
-                    self.teloxide_bot.send_message(
-                        Recipient::Id(ChatId(self.telegram_chat_id)),
-                        message).await;
+            TelegramSenderActorMessage::SendTelegramMessage {respond_to, message, parse_mode, server, channel} => {
+                let message_thread_id = self.message_thread_id(&server, channel.as_ref()).await;
+                self.send_message(message, parse_mode, &server, message_thread_id).await;
                 let _ = respond_to.send(());
             },
-            TelegramSenderActorMessage::UpdatePinnedMumbleStatusMessage {
-                respond_to, active_users
-            } => {
-                let mut message = "🎧 Mumble: 0 users online".to_string();
-                if !active_users.is_empty() {
-                    let user_count = active_users.len();
-                    let user_list = active_users.into_iter().map(|u| u.name).collect::<Vec<_>>().join(", ");
-                    message = format!("🎧 Mumble: {} users online ({})", user_count, user_list);
+            TelegramSenderActorMessage::QueueNotification {respond_to, notification, server, channel} => {
+                // A user may join in one channel and leave from another, whose notifications go to different topics
+                let cancelled = self.pending_notifications.iter_mut()
+                    .filter(|((batch_server, _), _)| *batch_server == server)
+                    .any(|(_, batch)| batch.cancel(&notification));
+                if !cancelled {
+                    let message_thread_id = self.message_thread_id(&server, channel.as_ref()).await;
+                    self.pending_notifications.entry((server, message_thread_id)).or_default().push(notification, Instant::now());
                 }
-
-                match self.pinned_mumble_status_message {
-                    Some(message_id) => {
-                        let _ = self.teloxide_bot.edit_message_text(
-                            Recipient::Id(ChatId(self.telegram_chat_id)),
-                            MessageId(message_id),
-                            message).await;
-                    },
-                    None => {
-                        let _ = self.teloxide_bot.send_message(
-                            Recipient::Id(ChatId(self.telegram_chat_id)),
-                            message).await;
-                    }
+                let _ = respond_to.send(());
+            },
+            TelegramSenderActorMessage::SendTelegramPhoto {respond_to, photo, html_caption, server, channel} => {
+                let message_thread_id = self.message_thread_id(&server, channel.as_ref()).await;
+                self.send_photo(photo, html_caption, &server, message_thread_id).await;
+                let _ = respond_to.send(());
+            },
+            TelegramSenderActorMessage::SyncForumTopic {respond_to, server, channel} => {
+                self.sync_forum_topic(&server, channel).await;
+                let _ = respond_to.send(());
+            },
+            TelegramSenderActorMessage::CloseForumTopic {respond_to, server, channel_id} => {
+                if self.forum_topics_enabled {
+                    self.close_forum_topic(&server, channel_id).await;
                 }
-
+                let _ = respond_to.send(());
+            },
+            TelegramSenderActorMessage::UpdatePinnedMumbleStatusMessage {
+                respond_to, server, status
+            } => {
+                // Only the latest state matters, edits are rate limited by the run loop
+                self.server_statuses.insert(server, status);
+                self.pinned_status_outdated = true;
                 let _ = respond_to.send(());
             }
         }
     }
 }
 
-async fn run_telegram_sender_actor(mut actor: TelegramSenderActor) {
+async fn run_telegram_sender_actor(mut actor: TelegramSenderActor, pinned_status: bool) {
     actor.load_state().await;
-    if actor.pinned_mumble_status_message.is_none() {
-        actor.create_pinned_mumble_status_message().await;
+    if pinned_status && actor.pinned_mumble_status_message.is_none() {
+        actor.create_pinned_mumble_status_message("🎧 Mumble".to_string(), None).await;
+    }
+    let mut pinned_status_check = time::interval_at(Instant::now() + PINNED_STATUS_CHECK_INTERVAL, PINNED_STATUS_CHECK_INTERVAL);
+    pinned_status_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let notification_deadline = actor.notification_deadline();
+        let pinned_status_deadline = actor.pinned_status_deadline();
+        tokio::select! {
+            msg = actor.receiver.recv() => match msg {
+                Some(msg) => actor.handle_message(msg).await,
+                None => break
+            },
+            _ = sleep_until(notification_deadline), if notification_deadline.is_some() => actor.flush_notifications(Instant::now()).await,
+            _ = sleep_until(pinned_status_deadline), if pinned_status_deadline.is_some() => actor.flush_pinned_status().await,
+            _ = pinned_status_check.tick(), if pinned_status => actor.ensure_status_message_pinned().await
+        }
     }
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg).await;
+
+    actor.flush_notifications(Instant::now() + actor.coalesce_window).await;
+    actor.flush_pinned_status().await;
+}
+
+/// Servers are only told apart in chats which get more than one of them.
+fn server_prefix<'a>(server_names: &'a BTreeMap<String, String>, server: &str) -> Option<&'a str> {
+    match server_names.len() > 1 {
+        true => server_names.get(server).map(String::as_str),
+        false => None
+    }
+}
+
+/// Telegram has no dedicated error for messages sent to a deleted forum topic.
+fn is_message_thread_not_found(err: &RequestError) -> bool {
+    matches!(err, RequestError::Api(ApiError::Unknown(message)) if message.contains("message thread not found"))
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    if let Some(deadline) = deadline {
+        time::sleep_until(deadline).await;
     }
 }
 
+/// Handle to the sender actor of one chat, along with the settings that decide what is sent to it.
 #[derive(Clone)]
 pub struct TelegramSenderActorHandle {
-    sender: mpsc::Sender<TelegramSenderActorMessage>
+    sender: mpsc::Sender<TelegramSenderActorMessage>,
+    chat_id: i64,
+    bridge: BridgeDirection,
+    pinned_status: bool,
+    forum_topics: bool,
+    weekly_talk_stats: bool,
+    server_names: Arc<BTreeMap<String, String>>,
+    templates: MessageTemplates
 }
 
 impl TelegramSenderActorHandle {
-    pub fn new(bot_settings: &TelegramSettings, state_file_actor_handle: StateFileActorHandle) -> (Self, JoinHandle<()>) {
+    pub fn new(
+        bot_settings: &TelegramSettings,
+        chat: &ChatSettings,
+        server_names: BTreeMap<String, String>,
+        templates: MessageTemplates,
+        username_mappings: Vec<UsernameMapping>,
+        state_file_actor_handle: StateFileActorHandle) -> (Self, JoinHandle<()>) {
         let (sender, receiver) = mpsc::channel(32);
-        let actor = TelegramSenderActor::new(receiver, state_file_actor_handle, bot_settings);
-        let actor_task = tokio::spawn(run_telegram_sender_actor(actor));
+        let pinned_status = chat.pinned_status.unwrap_or(true);
+        let server_names = Arc::new(server_names);
+        let actor = TelegramSenderActor::new(
+            receiver,
+            state_file_actor_handle,
+            bot_settings,
+            chat,
+            server_names.clone(),
+            templates.clone(),
+            username_mappings);
+        let actor_task = tokio::spawn(run_telegram_sender_actor(actor, pinned_status));
 
-        (Self {sender}, actor_task)
+        let handle = Self {
+            sender,
+            chat_id: chat.chat_id,
+            bridge: chat.bridge,
+            pinned_status,
+            forum_topics: chat.forum_topics,
+            weekly_talk_stats: chat.weekly_talk_stats,
+            server_names,
+            templates
+        };
+        (handle, actor_task)
     }
 
-    pub async fn send_telegram_message(&self, message: String) {
+    pub fn chat_id(&self) -> ChatId {
+        ChatId(self.chat_id)
+    }
+
+    pub fn bridge(&self) -> BridgeDirection {
+        self.bridge
+    }
+
+    pub fn forum_topics(&self) -> bool {
+        self.forum_topics
+    }
+
+    pub fn weekly_talk_stats(&self) -> bool {
+        self.weekly_talk_stats
+    }
+
+    pub fn templates(&self) -> &MessageTemplates {
+        &self.templates
+    }
+
+    pub fn relays_server(&self, server: &str) -> bool {
+        self.server_names.contains_key(server)
+    }
+
+    /// Display name to prefix messages of the server with, if the chat gets more than one server.
+    pub fn server_prefix(&self, server: &str) -> Option<&str> {
+        server_prefix(&self.server_names, server)
+    }
+
+    /// Queues a notification to be sent with others arriving within the coalescing window. Notifications about a
+    /// channel go to its forum topic if the chat has them enabled.
+    pub async fn queue_notification(&self, server: &str, notification: Notification, channel: Option<ChannelState>) {
+        let (send, recv) = oneshot::channel();
+        let msg = TelegramSenderActorMessage::QueueNotification {
+            respond_to: send,
+            notification,
+            server: server.to_string(),
+            channel
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor has been killed");
+    }
+
+    /// Sends a message formatted with Telegram's HTML subset, the caller is responsible for escaping it.
+    pub async fn send_telegram_html_message(&self, server: &str, message: String, channel: Option<ChannelState>) {
         let (send, recv) = oneshot::channel();
         let msg = TelegramSenderActorMessage::SendTelegramMessage {
             respond_to: send,
-            message
+            message,
+            parse_mode: Some(ParseMode::Html),
+            server: server.to_string(),
+            channel
         };
 
         let _ = self.sender.send(msg).await;
         recv.await.expect("Actor has been killed");
     }
 
-    pub async fn update_pinned_mumble_status_message(&self, active_users: Vec<UserState>) {
+    pub async fn send_telegram_photo(&self, server: &str, photo: Vec<u8>, html_caption: Option<String>, channel: Option<ChannelState>) {
+        let (send, recv) = oneshot::channel();
+        let msg = TelegramSenderActorMessage::SendTelegramPhoto {
+            respond_to: send,
+            photo,
+            html_caption,
+            server: server.to_string(),
+            channel
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor has been killed");
+    }
+
+    /// Creates or renames the forum topic of a channel, if the chat has forum topics enabled.
+    pub async fn sync_forum_topic(&self, server: &str, channel: ChannelState) {
+        let (send, recv) = oneshot::channel();
+        let msg = TelegramSenderActorMessage::SyncForumTopic {
+            respond_to: send,
+            server: server.to_string(),
+            channel
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor has been killed");
+    }
+
+    /// Closes the forum topic of a deleted channel, if the chat has one for it.
+    pub async fn close_forum_topic(&self, server: &str, channel_id: u32) {
+        let (send, recv) = oneshot::channel();
+        let msg = TelegramSenderActorMessage::CloseForumTopic {
+            respond_to: send,
+            server: server.to_string(),
+            channel_id
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor has been killed");
+    }
+
+    /// Does nothing for chats without a pinned status message.
+    pub async fn update_pinned_mumble_status_message(&self, server: &str, status: MumbleStatus) {
+        if !self.pinned_status {
+            return;
+        }
+
         let (send, recv) = oneshot::channel();
         let msg = TelegramSenderActorMessage::UpdatePinnedMumbleStatusMessage {
             respond_to: send,
-            active_users
+            server: server.to_string(),
+            status
         };
 
         let _ = self.sender.send(msg).await;
@@ -0,0 +1,204 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use fluent_bundle::FluentArgs;
+use handlebars::{no_escape, Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext};
+use log::error;
+use serde_json::{json, Value};
+use crate::i18n::Localizer;
+use crate::settings::TemplateSettings;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Template {
+    Join,
+    Leave,
+    ChannelSwitch,
+    Mute,
+    ChannelCreated,
+    ChannelDeleted,
+    PinnedStatus,
+    Who
+}
+
+const ALL_TEMPLATES: [Template; 8] = [
+    Template::Join,
+    Template::Leave,
+    Template::ChannelSwitch,
+    Template::Mute,
+    Template::ChannelCreated,
+    Template::ChannelDeleted,
+    Template::PinnedStatus,
+    Template::Who
+];
+
+impl Template {
+    pub fn name(self) -> &'static str {
+        match self {
+            Template::Join => "join",
+            Template::Leave => "leave",
+            Template::ChannelSwitch => "channel_switch",
+            Template::Mute => "mute",
+            Template::ChannelCreated => "channel_created",
+            Template::ChannelDeleted => "channel_deleted",
+            Template::PinnedStatus => "pinned_status",
+            Template::Who => "who"
+        }
+    }
+
+    /// Fluent message used when no template is configured.
+    fn message_id(self) -> &'static str {
+        match self {
+            Template::Join => "notification-join",
+            Template::Leave => "notification-leave",
+            Template::ChannelSwitch => "notification-channel-switch",
+            Template::Mute => "notification-mute",
+            Template::ChannelCreated => "notification-channel-created",
+            Template::ChannelDeleted => "notification-channel-deleted",
+            Template::PinnedStatus => "pinned-status",
+            Template::Who => "who"
+        }
+    }
+
+    fn configured_source(self, settings: &TemplateSettings) -> Option<&str> {
+        match self {
+            Template::Join => settings.join.as_deref(),
+            Template::Leave => settings.leave.as_deref(),
+            Template::ChannelSwitch => settings.channel_switch.as_deref(),
+            Template::Mute => settings.mute.as_deref(),
+            Template::ChannelCreated => settings.channel_created.as_deref(),
+            Template::ChannelDeleted => settings.channel_deleted.as_deref(),
+            Template::PinnedStatus => settings.pinned_status.as_deref(),
+            Template::Who => settings.who.as_deref()
+        }
+    }
+
+    /// Data with every variable the template gets, used to catch typos in configured templates at startup.
+    fn sample_data(self) -> Value {
+        match self {
+            Template::Join => json!({"user": "Alice and Bob", "users": ["Alice", "Bob"], "count": 2}),
+            Template::Leave => json!({"user": "Alice", "duration": "2h 13m", "duration_seconds": 7980}),
+            Template::ChannelSwitch => json!({"user": "Alice", "channel": "Lobby"}),
+            Template::Mute => json!({"user": "Alice", "state": "muted", "muted": true, "deafened": false}),
+            Template::ChannelCreated | Template::ChannelDeleted => json!({"channel": "Lobby"}),
+            Template::PinnedStatus => json!({
+                "count": 2,
+                "users": ["Alice", "Bob"],
+                "tree": "📂 Root",
+                "connected_for": "2h 13m",
+                "server": {"name": "localhost", "release": "1.5.0", "max_users": 100}
+            }),
+            Template::Who => json!({"count": 2, "tree": "📂 Root"})
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    template: &'static str,
+    reason: String
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid {} template: {}", self.template, self.reason)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// `{{list users}}` joins names into a list in the configured language.
+struct ListHelper {
+    localizer: Localizer
+}
+
+impl HelperDef for ListHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output) -> HelperResult {
+        let names: Vec<&str> = h.param(0)
+            .and_then(|param| param.value().as_array())
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        out.write(&self.localizer.list(&names))?;
+        Ok(())
+    }
+}
+
+/// Everything the bot posts. Configured handlebars templates take precedence, otherwise the message comes from the
+/// locale's Fluent catalogue. Values are inserted as is, templates producing HTML get already escaped values.
+#[derive(Clone)]
+pub struct MessageTemplates {
+    registry: Arc<Handlebars<'static>>,
+    localizer: Localizer
+}
+
+impl MessageTemplates {
+    pub fn new(settings: &TemplateSettings, localizer: Localizer) -> Result<Self, TemplateError> {
+        let mut registry = Handlebars::new();
+        registry.set_strict_mode(true);
+        registry.register_escape_fn(no_escape);
+        registry.register_helper("list", Box::new(ListHelper { localizer: localizer.clone() }));
+
+        for template in ALL_TEMPLATES {
+            let Some(source) = template.configured_source(settings) else {
+                continue;
+            };
+            registry.register_template_string(template.name(), source)
+                .map_err(|err| TemplateError { template: template.name(), reason: err.to_string() })?;
+            registry.render(template.name(), &template.sample_data())
+                .map_err(|err| TemplateError { template: template.name(), reason: err.to_string() })?;
+        }
+
+        Ok(Self { registry: Arc::new(registry), localizer })
+    }
+
+    pub fn localizer(&self) -> &Localizer {
+        &self.localizer
+    }
+
+    pub fn render(&self, template: Template, data: &Value) -> Option<String> {
+        if !self.registry.has_template(template.name()) {
+            let mut args = FluentArgs::new();
+            self.add_fluent_args(&mut args, "", data);
+            return Some(self.localizer.message(template.message_id(), Some(&args)));
+        }
+
+        match self.registry.render(template.name(), data) {
+            Ok(rendered) => Some(rendered),
+            Err(err) => {
+                error!("Failed to render {} template: {}", template.name(), err);
+                None
+            }
+        }
+    }
+
+    /// Flattens the template data into Fluent arguments, nested values are joined with `_` (`server_release`)
+    /// and lists of names are joined into a localized list.
+    fn add_fluent_args(&self, args: &mut FluentArgs, name: &str, value: &Value) {
+        match value {
+            Value::Object(fields) => {
+                for (field, value) in fields {
+                    let name = match name.is_empty() {
+                        true => field.clone(),
+                        false => format!("{}_{}", name, field)
+                    };
+                    self.add_fluent_args(args, &name, value);
+                }
+            },
+            Value::Array(values) => {
+                let names: Vec<&str> = values.iter().filter_map(Value::as_str).collect();
+                args.set(name.to_string(), self.localizer.list(&names));
+            },
+            Value::String(value) => args.set(name.to_string(), value.clone()),
+            Value::Number(number) => match number.as_i64() {
+                Some(number) => args.set(name.to_string(), number),
+                None => args.set(name.to_string(), number.as_f64().unwrap_or_default())
+            },
+            Value::Bool(value) => args.set(name.to_string(), value.to_string()),
+            Value::Null => args.set(name.to_string(), "")
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use crate::settings::UsernameMapping;
+use crate::state_file_actor::StateFileActorHandle;
+
+/// Maps mumble user names to Telegram mentions. Mappings from the configuration take precedence over
+/// links users created themselves with `/link`.
+#[derive(Debug, Clone, Default)]
+pub struct UsernameMap {
+    mumble_to_telegram: HashMap<String, String>
+}
+
+/// Links a mumble name to a Telegram mention in the `/link` state, unlinking the mention from any other name as an
+/// account can only be linked to one name at a time. Fails with the current mention if the name is already linked
+/// to a different account, so nobody can take over someone else's name.
+pub fn link_username(links: &mut HashMap<String, String>, mumble_name: &str, mention: &str) -> Result<(), String> {
+    if let Some(linked_mention) = links.get(mumble_name).filter(|linked_mention| !linked_mention.eq_ignore_ascii_case(mention)) {
+        return Err(linked_mention.clone());
+    }
+
+    links.retain(|_, linked_mention| !linked_mention.eq_ignore_ascii_case(mention));
+    links.insert(mumble_name.to_string(), mention.to_string());
+    Ok(())
+}
+
+impl UsernameMap {
+    pub fn new(configured: &[UsernameMapping], linked: &HashMap<String, String>) -> Self {
+        let mut mumble_to_telegram = linked.clone();
+        for mapping in configured {
+            mumble_to_telegram.insert(mapping.mumble.clone(), mapping.telegram.clone());
+        }
+
+        Self { mumble_to_telegram }
+    }
+
+    pub async fn load(configured: &[UsernameMapping], state_file_actor_handle: &StateFileActorHandle) -> Self {
+        let state = state_file_actor_handle.get_state().await;
+        Self::new(configured, &state.username_links)
+    }
+
+    pub fn telegram_mention(&self, mumble_name: &str) -> Option<&str> {
+        self.mumble_to_telegram.get(mumble_name).map(String::as_str)
+    }
+
+    /// Finds the mumble name mapped to a Telegram username, with or without the leading `@`.
+    pub fn mumble_name(&self, telegram_username: &str) -> Option<&str> {
+        let mention = format!("@{}", telegram_username.trim_start_matches('@'));
+        self.mumble_to_telegram.iter()
+            .find(|(_, telegram)| telegram.eq_ignore_ascii_case(&mention))
+            .map(|(mumble, _)| mumble.as_str())
+    }
+
+    /// The mumble name followed by the Telegram mention if the user is mapped, e.g. `Will (@MrCactuso)`.
+    pub fn display_name(&self, mumble_name: &str) -> String {
+        match self.telegram_mention(mumble_name) {
+            Some(mention) => format!("{} ({})", mumble_name, mention),
+            None => mumble_name.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_and_relinks_own_account() {
+        let mut links = HashMap::new();
+        assert_eq!(link_username(&mut links, "Alice", "@alice"), Ok(()));
+        assert_eq!(link_username(&mut links, "Alice", "@Alice"), Ok(()));
+        assert_eq!(link_username(&mut links, "Alice2", "@alice"), Ok(()));
+
+        assert_eq!(links, HashMap::from([("Alice2".to_string(), "@alice".to_string())]));
+    }
+
+    #[test]
+    fn refuses_name_linked_to_another_account() {
+        let mut links = HashMap::from([("Alice".to_string(), "@alice".to_string()), ("Mallory".to_string(), "@mallory".to_string())]);
+        assert_eq!(link_username(&mut links, "Alice", "@mallory"), Err("@alice".to_string()));
+
+        // The attempt leaves the existing links alone
+        assert_eq!(links.get("Alice").map(String::as_str), Some("@alice"));
+        assert_eq!(links.get("Mallory").map(String::as_str), Some("@mallory"));
+    }
+}